@@ -0,0 +1,23 @@
+// insert, remove and contains report whether anything happened; with this deny in place,
+// a bare `tree.insert(3);` fails to compile with "unused return value ... that must be used"
+#![deny(unused_must_use)]
+
+use learn_rust_ds::avl_vec::Tree;
+use learn_rust_ds::heapq::HeapQ;
+use learn_rust_ds::tree::TreeOps;
+
+fn main() {
+    let mut tree = Tree::new();
+    for value in [3, 1, 3] {
+        if !tree.insert(value) {
+            println!("{value} was already present");
+        }
+    }
+    // discarding on purpose has to be spelled out
+    let _ = tree.remove(&1);
+    println!("contains 1: {}", tree.contains(&1));
+
+    let mut heap = HeapQ::from(vec![2, 7, 5]);
+    println!("top: {:?}", heap.top());
+    println!("popped: {:?}", heap.pop());
+}
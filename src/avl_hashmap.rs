@@ -1,12 +1,17 @@
-use crate::tree::TreeOps;
+#[cfg(feature = "stats")]
+use crate::tree::CountingKey;
+use crate::tree::{self, DuplicatePolicy, TreeOps};
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
+use std::iter::FusedIterator;
 
 pub struct Tree<T: Ord> {
     items: HashMap<usize, Node<T>>,
     root: Option<usize>,
     len: usize,
     id_gen: usize,
+    duplicate_policy: DuplicatePolicy,
 }
 
 struct Node<T> {
@@ -24,6 +29,7 @@ pub struct IntoIter<T: Ord> {
 pub struct Iter<'a, T: Ord> {
     tree: &'a Tree<T>,
     next: Option<usize>,
+    remaining: usize,
 }
 
 impl<T: Ord> TreeOps<T> for Tree<T> {
@@ -32,7 +38,12 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
         if let Some(index) = closest {
             let node = self.unwrap_occupied(index);
             match value.cmp(&node.value) {
-                Ordering::Equal => return false,
+                Ordering::Equal => {
+                    if self.duplicate_policy == DuplicatePolicy::Replace {
+                        self.unwrap_occupied_mut(index).value = value;
+                    }
+                    return false;
+                }
                 ord => {
                     let new = self.insert_node(value, Some(index));
                     let node = self.unwrap_occupied_mut(index);
@@ -53,7 +64,7 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
     }
 
     fn remove(&mut self, value: &T) -> bool {
-        let closest = self.find_closest(&value);
+        let closest = self.find_closest(value);
         let Some(index) = closest else {
             return false;
         };
@@ -80,6 +91,21 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
     fn len(&self) -> usize {
         self.len
     }
+
+    fn height(&self) -> i32 {
+        self.height()
+    }
+
+    fn is_balanced(&self) -> bool {
+        tree::is_balanced_by(self.root, |index| {
+            let node = self.unwrap_occupied(index);
+            (node.left, node.right)
+        })
+    }
+
+    fn boxed_iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.iter())
+    }
 }
 
 impl<T: Ord> Tree<T> {
@@ -89,25 +115,36 @@ impl<T: Ord> Tree<T> {
             root: None,
             len: 0,
             id_gen: 0,
+            duplicate_policy: DuplicatePolicy::Reject,
         }
     }
 
-    #[cfg(test)]
-    fn height(&self) -> i32 {
+    // edges on the longest path from the root to a leaf, -1 for an empty tree
+    pub fn height(&self) -> i32 {
         match self.root {
             None => -1,
             Some(index) => self.unwrap_occupied(index).height,
         }
     }
 
-    pub fn into_iter(self) -> IntoIter<T> {
-        IntoIter { tree: self }
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    // inherent copies of the TreeOps methods, so callers don't need the trait in scope
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn iter(&self) -> Iter<'_, T> {
         Iter {
             tree: self,
             next: self.first(),
+            remaining: self.len,
         }
     }
 
@@ -123,7 +160,7 @@ impl<T: Ord> Tree<T> {
                 Ordering::Equal => return cur,
             }
         }
-        return prev;
+        prev
     }
 
     fn insert_node(&mut self, value: T, parent: Option<usize>) -> usize {
@@ -261,17 +298,13 @@ impl<T: Ord> Tree<T> {
     }
 
     fn rotate_right(&mut self, x_link: Option<usize>) -> Option<usize> {
-        let Some(x_index) = x_link else {
-            return None;
-        };
+        let x_index = x_link?;
         let (x_left, x_parent) = {
             let x = self.unwrap_occupied(x_index);
             (x.left, x.parent)
         };
         let y_link = x_left;
-        let Some(y_index) = y_link else {
-            return None;
-        };
+        let y_index = y_link?;
         let t2_link = self.unwrap_occupied(y_index).right;
 
         // fix parent -> y
@@ -301,17 +334,13 @@ impl<T: Ord> Tree<T> {
     }
 
     fn rotate_left(&mut self, x_link: Option<usize>) -> Option<usize> {
-        let Some(x_index) = x_link else {
-            return None;
-        };
+        let x_index = x_link?;
         let (x_right, x_parent) = {
             let x = self.unwrap_occupied(x_index);
             (x.right, x.parent)
         };
         let y_link = x_right;
-        let Some(y_index) = y_link else {
-            return None;
-        };
+        let y_index = y_link?;
         let t2_link = self.unwrap_occupied(y_index).left;
 
         // fix parent -> y
@@ -362,7 +391,7 @@ impl<T: Ord> Tree<T> {
             let node = self.unwrap_occupied(index);
             let left_height = self.link_height(node.left);
             let right_height = self.link_height(node.right);
-            return left_height - right_height;
+            left_height - right_height
         } else {
             0
         }
@@ -381,9 +410,7 @@ impl<T: Ord> Tree<T> {
 
     fn before_sub(&self, index: usize) -> Option<usize> {
         let node = self.unwrap_occupied(index);
-        let Some(mut cur) = node.left else {
-            return None;
-        };
+        let mut cur = node.left?;
 
         while let Some(right) = self.unwrap_occupied(cur).right {
             cur = right;
@@ -413,9 +440,7 @@ impl<T: Ord> Tree<T> {
 
     fn after_sub(&self, index: usize) -> Option<usize> {
         let node = self.unwrap_occupied(index);
-        let Some(mut cur) = node.right else {
-            return None;
-        };
+        let mut cur = node.right?;
 
         while let Some(left) = self.unwrap_occupied(cur).left {
             cur = left;
@@ -455,11 +480,60 @@ impl<T: Ord> Tree<T> {
     }
 }
 
+impl<T: Ord + fmt::Debug> fmt::Debug for Tree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+// Counts with tree::CountingKey, so only a tree of CountingKey values has a count. The count
+// is per thread, and insert, remove and contains each compare once more after find_closest,
+// since it returns only the closest node.
+#[cfg(feature = "stats")]
+impl<T: Ord> Tree<CountingKey<T>> {
+    // value comparisons since the last reset
+    pub fn comparison_count(&self) -> u64 {
+        tree::comparison_count()
+    }
+
+    pub fn reset_comparison_count(&mut self) {
+        tree::take_comparison_count();
+    }
+}
+
+impl<T: Ord> Default for Tree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> IntoIterator for Tree<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { tree: self }
+    }
+}
+
+impl<'a, T: Ord> IntoIterator for &'a Tree<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
 impl<T: Ord> Iterator for IntoIter<T> {
     type Item = T;
     fn next(&mut self) -> Option<Self::Item> {
         self.tree.first().map(|ptr| self.tree.remove_node(ptr))
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.tree.len, Some(self.tree.len))
+    }
 }
 
 impl<'a, T: Ord> Iterator for Iter<'a, T> {
@@ -468,19 +542,24 @@ impl<'a, T: Ord> Iterator for Iter<'a, T> {
         self.next.map(|index| {
             let node = self.tree.unwrap_occupied(index);
             self.next = self.tree.after(index);
+            self.remaining -= 1;
             &node.value
         })
     }
-}
 
-impl<T: Ord> Drop for Tree<T> {
-    fn drop(&mut self) {
-        while let Some(ptr) = self.root {
-            self.remove_node(ptr);
-        }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
+impl<T: Ord> ExactSizeIterator for IntoIter<T> {}
+
+impl<T: Ord> FusedIterator for IntoIter<T> {}
+
+impl<T: Ord> ExactSizeIterator for Iter<'_, T> {}
+
+impl<T: Ord> FusedIterator for Iter<'_, T> {}
+
 impl<T: Ord> Node<T> {
     pub fn new(value: T) -> Self {
         Node {
@@ -508,7 +587,7 @@ mod tests {
         let mut tree = Tree::<i32>::new();
         for i in 0..10 {
             assert_eq!(tree.len(), i as usize);
-            tree.insert(i);
+            assert!(tree.insert(i));
             assert!(tree.contains(&i));
         }
         for i in 0..10 {
@@ -523,7 +602,7 @@ mod tests {
         let size = 1000;
         for i in 0..size {
             assert_eq!(tree.len(), i as usize);
-            tree.insert(i);
+            assert!(tree.insert(i));
             assert!(tree.contains(&i));
         }
         for i in 0..size {
@@ -537,7 +616,7 @@ mod tests {
     fn remove_and_contains() {
         let mut tree = Tree::<i32>::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
         for i in 0..10 {
             assert!(tree.contains(&i));
@@ -566,7 +645,7 @@ mod tests {
     fn first_after_asc_insert() {
         let mut tree = Tree::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
         assert_eq!(
             tree.first().map(|index| tree.unwrap_occupied(index).value),
@@ -578,7 +657,7 @@ mod tests {
     fn first_after_desc_insert() {
         let mut tree = Tree::new();
         for i in (0..10).rev() {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
         assert_eq!(
             tree.first().map(|index| tree.unwrap_occupied(index).value),
@@ -590,7 +669,7 @@ mod tests {
     fn into_iter_asc() {
         let mut tree = Tree::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.into_iter();
@@ -604,7 +683,7 @@ mod tests {
     fn into_iter_desc() {
         let mut tree = Tree::new();
         for i in (0..10).rev() {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.into_iter();
@@ -618,7 +697,7 @@ mod tests {
     fn iter_asc() {
         let mut tree = Tree::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.iter();
@@ -632,7 +711,7 @@ mod tests {
     fn iter_desc() {
         let mut tree = Tree::new();
         for i in (0..10).rev() {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.iter();
@@ -641,4 +720,23 @@ mod tests {
         }
         assert_eq!(iter.next(), None);
     }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn comparison_count_single_element_lookup() {
+        use crate::tree::CountingKey;
+
+        let mut tree = Tree::new();
+        assert!(tree.insert(CountingKey(5)));
+        tree.reset_comparison_count();
+
+        // one comparison to find the root, one more to confirm it is equal
+        assert!(tree.contains(&CountingKey(5)));
+        assert_eq!(tree.comparison_count(), 2);
+        assert!(!tree.contains(&CountingKey(7)));
+        assert_eq!(tree.comparison_count(), 4);
+
+        tree.reset_comparison_count();
+        assert_eq!(tree.comparison_count(), 0);
+    }
 }
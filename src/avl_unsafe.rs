@@ -1,11 +1,14 @@
-use crate::tree::TreeOps;
+use crate::tree::{self, DuplicatePolicy, TreeOps};
 use std::cmp::Ordering;
+use std::fmt;
+use std::iter::FusedIterator;
 use std::marker::PhantomData;
 use std::ptr::NonNull;
 
 pub struct Tree<T: Ord> {
     root: Link<T>,
     len: usize,
+    duplicate_policy: DuplicatePolicy,
     _marker: PhantomData<T>,
 }
 
@@ -25,6 +28,9 @@ pub struct IntoIter<T: Ord> {
 
 pub struct Iter<'a, T: Ord> {
     next: Link<T>,
+    next_back: Link<T>,
+    // the cursors have met once this reaches zero
+    remaining: usize,
     _marker: PhantomData<&'a T>,
 }
 
@@ -37,7 +43,12 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
                 let node = ptr.as_mut();
 
                 match value.cmp(&node.value) {
-                    Ordering::Equal => return false,
+                    Ordering::Equal => {
+                        if self.duplicate_policy == DuplicatePolicy::Replace {
+                            node.value = value;
+                        }
+                        return false;
+                    }
                     ord => {
                         let mut new = self.node_for_value(value);
                         if ord == Ordering::Less {
@@ -60,7 +71,7 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
     }
 
     fn remove(&mut self, value: &T) -> bool {
-        let closest = self.find_closest(&value);
+        let closest = self.find_closest(value);
         let Some(ptr) = closest else {
             return false;
         };
@@ -73,7 +84,7 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
         }
 
         self.remove_node(ptr);
-        return true;
+        true
     }
 
     fn contains(&self, value: &T) -> bool {
@@ -92,6 +103,22 @@ impl<T: Ord> TreeOps<T> for Tree<T> {
     fn len(&self) -> usize {
         self.len
     }
+
+    fn height(&self) -> i32 {
+        self.height()
+    }
+
+    fn is_balanced(&self) -> bool {
+        tree::is_balanced_by(self.root, |ptr| {
+            // SAFETY: links only ever hold pointers created by node_for_value
+            let node = unsafe { ptr.as_ref() };
+            (node.left, node.right)
+        })
+    }
+
+    fn boxed_iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.iter())
+    }
 }
 
 impl<T: Ord> Tree<T> {
@@ -99,34 +126,71 @@ impl<T: Ord> Tree<T> {
         Tree {
             root: None,
             len: 0,
+            duplicate_policy: DuplicatePolicy::Reject,
             _marker: PhantomData,
         }
     }
 
-    #[cfg(test)]
-    fn height(&self) -> i32 {
+    // edges on the longest path from the root to a leaf, -1 for an empty tree
+    pub fn height(&self) -> i32 {
         match self.root {
             None => -1,
             Some(ptr) => unsafe { ptr.as_ref().height },
         }
     }
 
-    pub fn into_iter(self) -> IntoIter<T> {
-        IntoIter { tree: self }
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
+    }
+
+    // inherent copies of the TreeOps methods, so callers don't need the trait in scope
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn iter<'a>(&'a self) -> Iter<'a, T> {
         Iter {
             next: self.first(),
+            next_back: self.last(),
+            remaining: self.len,
             _marker: PhantomData,
         }
     }
 
+    // O(n): every child points back at its parent, the node count matches len, and an
+    // in-order walk is strictly ascending; heights and balance are not checked
+    pub fn is_valid_bst(&self) -> bool {
+        if let Some(root) = self.root
+            && unsafe { root.as_ref().parent.is_some() }
+        {
+            return false;
+        }
+        let mut count = 0;
+        let mut stack: Vec<NonNull<Node<T>>> = self.root.into_iter().collect();
+        while let Some(ptr) = stack.pop() {
+            count += 1;
+            // SAFETY: links only ever hold pointers created by node_for_value
+            let node = unsafe { ptr.as_ref() };
+            for child in [node.left, node.right].into_iter().flatten() {
+                if unsafe { child.as_ref().parent } != Some(ptr) {
+                    return false;
+                }
+                stack.push(child);
+            }
+        }
+        // the walk below follows parent links, so it only runs once they are known to be sound
+        count == self.len && self.iter().zip(self.iter().skip(1)).all(|(a, b)| a < b)
+    }
+
     fn node_for_value(&self, value: T) -> NonNull<Node<T>> {
         // SAFETY: we just created raw pointer to non null box
         unsafe {
             NonNull::new_unchecked(Box::into_raw(Box::new(Node {
-                value: value,
+                value,
                 height: 0,
                 left: None,
                 right: None,
@@ -150,7 +214,7 @@ impl<T: Ord> Tree<T> {
                 }
             }
         }
-        return prev;
+        prev
     }
 
     fn remove_node(&mut self, node_ptr: NonNull<Node<T>>) -> T {
@@ -235,6 +299,19 @@ impl<T: Ord> Tree<T> {
         }
     }
 
+    fn last(&self) -> Link<T> {
+        unsafe {
+            let mut cur = self.root;
+            while let Some(cur_ptr) = cur {
+                match cur_ptr.as_ref().right {
+                    None => return cur,
+                    right => cur = right,
+                }
+            }
+            None
+        }
+    }
+
     fn rebalance_ancestors(&mut self, link: Link<T>) {
         let mut cur = link;
         while let Some(ptr) = cur {
@@ -274,14 +351,10 @@ impl<T: Ord> Tree<T> {
 
     fn rotate_right(&mut self, x_link: Link<T>) -> Link<T> {
         unsafe {
-            let Some(mut x_ptr) = x_link else {
-                return None;
-            };
+            let mut x_ptr = x_link?;
             let x = x_ptr.as_mut();
             let y_link = x.left;
-            let Some(mut y_ptr) = y_link else {
-                return None;
-            };
+            let mut y_ptr = y_link?;
             let y = y_ptr.as_mut();
             let t2 = y.right;
 
@@ -314,14 +387,10 @@ impl<T: Ord> Tree<T> {
 
     fn rotate_left(&mut self, x_link: Link<T>) -> Link<T> {
         unsafe {
-            let Some(mut x_ptr) = x_link else {
-                return None;
-            };
+            let mut x_ptr = x_link?;
             let x = x_ptr.as_mut();
             let y_link = x.right;
-            let Some(mut y_ptr) = y_link else {
-                return None;
-            };
+            let mut y_ptr = y_link?;
             let y = y_ptr.as_mut();
             let t2 = y.left;
 
@@ -379,7 +448,7 @@ impl<T: Ord> Tree<T> {
                 let node = ptr.as_ref();
                 let left_height = self.link_height(node.left);
                 let right_height = self.link_height(node.right);
-                return left_height - right_height;
+                left_height - right_height
             }
         } else {
             0
@@ -394,35 +463,107 @@ impl<T: Ord> Tree<T> {
     }
 }
 
+impl<T: Ord + fmt::Debug> fmt::Debug for Tree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Ord> Default for Tree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> IntoIterator for Tree<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { tree: self }
+    }
+}
+
+impl<'a, T: Ord> IntoIterator for &'a Tree<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
 impl<T: Ord> Iterator for IntoIter<T> {
     type Item = T;
     fn next(&mut self) -> Option<Self::Item> {
         self.tree.first().map(|ptr| self.tree.remove_node(ptr))
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.tree.len, Some(self.tree.len))
+    }
 }
 
 impl<'a, T: Ord> Iterator for Iter<'a, T> {
     type Item = &'a T;
     fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
         unsafe {
             self.next.map(|ptr| {
                 let node = ptr.as_ref();
                 self.next = node.after();
+                self.remaining -= 1;
+                &node.value
+            })
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: Ord> DoubleEndedIterator for Iter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        unsafe {
+            self.next_back.map(|ptr| {
+                let node = ptr.as_ref();
+                self.next_back = node.before();
+                self.remaining -= 1;
                 &node.value
             })
         }
     }
 }
 
+impl<T: Ord> ExactSizeIterator for IntoIter<T> {}
+
+impl<T: Ord> FusedIterator for IntoIter<T> {}
+
+impl<T: Ord> ExactSizeIterator for Iter<'_, T> {}
+
+impl<T: Ord> FusedIterator for Iter<'_, T> {}
+
 fn eq_link_and_node<T: Ord>(a_link: Link<T>, b_ptr: &Node<T>) -> bool {
-    a_link.map_or(false, |a_ptr| std::ptr::eq(a_ptr.as_ptr(), b_ptr))
+    a_link.is_some_and(|a_ptr| std::ptr::eq(a_ptr.as_ptr(), b_ptr))
 }
 
 impl<T: Ord> Drop for Tree<T> {
     fn drop(&mut self) {
-        while let Some(ptr) = self.root {
-            self.remove_node(ptr);
+        // free every node directly, there is no need to unlink or rebalance
+        let mut stack: Vec<NonNull<Node<T>>> = self.root.take().into_iter().collect();
+        while let Some(ptr) = stack.pop() {
+            // SAFETY: every node is reachable from root exactly once and freed exactly once
+            let node = unsafe { Box::from_raw(ptr.as_ptr()) };
+            stack.extend(node.left);
+            stack.extend(node.right);
         }
+        self.len = 0;
     }
 }
 
@@ -432,9 +573,7 @@ impl<T: Ord> Node<T> {
     }
 
     fn before_sub(&self) -> Link<T> {
-        let Some(mut cur) = self.left else {
-            return None;
-        };
+        let mut cur = self.left?;
 
         unsafe {
             while let Some(right) = cur.as_ref().right {
@@ -464,9 +603,7 @@ impl<T: Ord> Node<T> {
     }
 
     fn after_sub(&self) -> Link<T> {
-        let Some(mut cur) = self.right else {
-            return None;
-        };
+        let mut cur = self.right?;
 
         unsafe {
             while let Some(left) = cur.as_ref().left {
@@ -507,7 +644,7 @@ mod tests {
         let mut tree = Tree::<i32>::new();
         for i in 0..10 {
             assert_eq!(tree.len(), i as usize);
-            tree.insert(i);
+            assert!(tree.insert(i));
             assert!(tree.contains(&i));
         }
         for i in 0..10 {
@@ -522,7 +659,7 @@ mod tests {
         let size = 1000;
         for i in 0..size {
             assert_eq!(tree.len(), i as usize);
-            tree.insert(i);
+            assert!(tree.insert(i));
             assert!(tree.contains(&i));
         }
         for i in 0..size {
@@ -536,7 +673,7 @@ mod tests {
     fn remove_and_contains() {
         let mut tree = Tree::<i32>::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
         for i in 0..10 {
             assert!(tree.contains(&i));
@@ -565,7 +702,7 @@ mod tests {
     fn first_after_asc_insert() {
         let mut tree = Tree::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
         assert_eq!(
             tree.first().map(|ptr| unsafe { ptr.as_ref().value }),
@@ -577,7 +714,7 @@ mod tests {
     fn first_after_desc_insert() {
         let mut tree = Tree::new();
         for i in (0..10).rev() {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
         assert_eq!(
             tree.first().map(|ptr| unsafe { ptr.as_ref().value }),
@@ -589,7 +726,7 @@ mod tests {
     fn into_iter_asc() {
         let mut tree = Tree::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.into_iter();
@@ -603,7 +740,7 @@ mod tests {
     fn into_iter_desc() {
         let mut tree = Tree::new();
         for i in (0..10).rev() {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.into_iter();
@@ -617,7 +754,7 @@ mod tests {
     fn iter_asc() {
         let mut tree = Tree::new();
         for i in 0..10 {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.iter();
@@ -631,7 +768,7 @@ mod tests {
     fn iter_desc() {
         let mut tree = Tree::new();
         for i in (0..10).rev() {
-            tree.insert(i);
+            assert!(tree.insert(i));
         }
 
         let mut iter = tree.iter();
@@ -640,4 +777,134 @@ mod tests {
         }
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn iter_rev_and_double_ended() {
+        let mut tree = Tree::new();
+        for i in [5, 2, 8, 1, 9, 3, 7, 4, 6, 0] {
+            assert!(tree.insert(i));
+        }
+        assert!(tree.iter().rev().copied().eq((0..10).rev()));
+
+        let mut iter = tree.iter();
+        let mut seen = Vec::new();
+        while let Some(&front) = iter.next() {
+            seen.push(front);
+            match iter.next_back() {
+                Some(&back) => seen.push(back),
+                None => break,
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (0..10).collect::<Vec<_>>());
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+        assert_eq!(Tree::<i32>::new().iter().next_back(), None);
+    }
+
+    #[test]
+    fn valid_bst_after_inserts_and_removals() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(888);
+        let mut tree = Tree::new();
+        assert!(tree.is_valid_bst());
+        for _ in 0..2000 {
+            let value: i32 = rng.random_range(0..300);
+            if rng.random_bool(0.6) {
+                let _ = tree.insert(value);
+            } else {
+                let _ = tree.remove(&value);
+            }
+            assert!(tree.is_valid_bst());
+        }
+    }
+
+    #[test]
+    fn valid_bst_detects_bad_parent() {
+        let mut tree = Tree::new();
+        for i in 0..7 {
+            assert!(tree.insert(i));
+        }
+        assert!(tree.is_valid_bst());
+        unsafe {
+            let mut root = tree.root.unwrap();
+            let mut left = root.as_ref().left.unwrap();
+            left.as_mut().parent = None;
+            assert!(!tree.is_valid_bst());
+            left.as_mut().parent = Some(root);
+
+            // swapping the root's children breaks the order but keeps the parent links
+            let root_node = root.as_mut();
+            std::mem::swap(&mut root_node.left, &mut root_node.right);
+        }
+        assert!(!tree.is_valid_bst());
+        unsafe {
+            let root_node = tree.root.unwrap().as_mut();
+            std::mem::swap(&mut root_node.left, &mut root_node.right);
+        }
+        assert!(tree.is_valid_bst());
+    }
+
+    #[test]
+    fn remove_root_with_two_children() {
+        let mut tree = Tree::new();
+        for i in 0..15 {
+            assert!(tree.insert(i));
+        }
+        let mut expected: Vec<i32> = (0..15).collect();
+        let mut removed = 0;
+        while let Some(root) = tree.root.map(|root| unsafe { root.as_ref() })
+            && root.left.is_some()
+            && root.right.is_some()
+        {
+            let value = root.value;
+            assert!(tree.remove(&value));
+            expected.retain(|v| *v != value);
+            assert_eq!(tree.len(), expected.len());
+            assert!(tree.is_valid_bst());
+            assert!(tree.iter().eq(expected.iter()));
+            removed += 1;
+        }
+        assert!(removed > 5);
+    }
+
+    #[test]
+    fn remove_with_predecessor_as_direct_left_child() {
+        let mut tree = Tree::new();
+        for i in [4, 2, 6, 5, 7] {
+            assert!(tree.insert(i));
+        }
+        // 2 has no right child, so it is 4's predecessor and moves straight up
+        let root = tree.root.unwrap();
+        assert_eq!(unsafe { root.as_ref().left.unwrap().as_ref().value }, 2);
+        assert!(tree.remove(&4));
+        assert_eq!(tree.len(), 4);
+        assert!(tree.is_valid_bst());
+        assert!(tree.is_balanced());
+        assert!(tree.iter().eq([2, 5, 6, 7].iter()));
+    }
+
+    #[test]
+    fn len_exact_when_removing_until_empty() {
+        use rand::SeedableRng;
+        use rand::seq::SliceRandom;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(910);
+        let mut values: Vec<i32> = (0..200).collect();
+        let mut tree = Tree::new();
+        for &value in &values {
+            assert!(tree.insert(value));
+        }
+        values.shuffle(&mut rng);
+        for (removed, value) in values.iter().enumerate() {
+            assert!(tree.remove(value));
+            assert!(!tree.remove(value));
+            assert_eq!(tree.len(), values.len() - removed - 1);
+            assert!(tree.is_valid_bst());
+        }
+        assert!(tree.is_empty());
+        assert!(tree.root.is_none());
+        assert_eq!(tree.iter().next(), None);
+    }
 }
@@ -1,670 +1,4473 @@
-use crate::tree::TreeOps;
-use std::cmp::Ordering;
+use crate::heapq::HeapQ;
+use crate::tree::{self, DuplicateError, DuplicatePolicy, TreeOps};
+use std::cmp::{Ordering, Reverse};
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Write};
+use std::hash::{Hash, Hasher};
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
 use std::mem::replace;
+use std::ops::{Bound, RangeBounds};
 
-pub struct Tree<T: Ord> {
-    items: Vec<Slot<T>>,
-    head_free: Option<usize>,
-    root: Option<usize>,
+// Arena index type. Every link is an Option of it, so u32 roughly halves the link overhead
+// of usize for trees that stay below 4 billion nodes.
+pub trait Index: Copy + Eq + Display {
+    fn from_usize(index: usize) -> Self;
+    fn to_usize(self) -> usize;
+}
+
+impl Index for usize {
+    fn from_usize(index: usize) -> Self {
+        index
+    }
+
+    fn to_usize(self) -> usize {
+        self
+    }
+}
+
+impl Index for u32 {
+    fn from_usize(index: usize) -> Self {
+        u32::try_from(index).expect("Arena index does not fit in u32")
+    }
+
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
+// Distance between two values for Tree::nearest, measured without overflowing: the
+// difference of two signed values may not fit their own type, so it is unsigned.
+pub trait AbsDiff: Copy {
+    type Output: Ord;
+    fn abs_diff(self, other: Self) -> Self::Output;
+}
+
+macro_rules! impl_abs_diff {
+    ($($t:ty => $out:ty),*) => {
+        $(impl AbsDiff for $t {
+            type Output = $out;
+            fn abs_diff(self, other: Self) -> $out {
+                <$t>::abs_diff(self, other)
+            }
+        })*
+    };
+}
+
+impl_abs_diff!(
+    i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize,
+    u8 => u8, u16 => u16, u32 => u32, u64 => u64, u128 => u128, usize => usize
+);
+
+pub struct Tree<T, Idx: Index = usize> {
+    items: Vec<Slot<T, Idx>>,
+    head_free: Option<Idx>,
+    root: Option<Idx>,
     len: usize,
+    shrink_threshold: Option<f64>,
+    duplicate_policy: DuplicatePolicy,
+    rebalance: bool,
+    // set on TreeArena's tree, whose arena also holds the nodes of the other trees
+    shares_arena: bool,
+    // handed to each new node, so a NodeRef can tell its node from a later one in the same slot
+    next_generation: u64,
+    #[cfg(feature = "stats")]
+    rotation_count: u64,
+    #[cfg(debug_assertions)]
+    mod_count: u64,
 }
 
-struct Node<T> {
+#[derive(Clone)]
+struct Node<T, Idx> {
     value: T,
     height: i32,
-    parent: Option<usize>,
-    left: Option<usize>,
-    right: Option<usize>,
+    generation: u64,
+    parent: Option<Idx>,
+    left: Option<Idx>,
+    right: Option<Idx>,
+}
+
+// a handle to one node that goes stale once the node is removed or the arena is rebuilt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeRef {
+    index: usize,
+    generation: u64,
+}
+
+// where a value sits: its neighbours when present, its floor and ceiling when absent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Located<'a, T> {
+    pub exists: bool,
+    pub prev: Option<&'a T>,
+    pub next: Option<&'a T>,
+}
+
+// A stored value equal to the one passed to Tree::entry, or the spot where it would go.
+// Values changed through an entry must keep their order, as with RangeMut.
+pub enum Entry<'a, T: Ord, Idx: Index = usize> {
+    Occupied(OccupiedEntry<'a, T, Idx>),
+    Vacant(VacantEntry<'a, T, Idx>),
+}
+
+pub struct OccupiedEntry<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a mut Tree<T, Idx>,
+    index: Idx,
+}
+
+// holds on to the search result, so inserting needs no second descent
+pub struct VacantEntry<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a mut Tree<T, Idx>,
+    value: T,
+    closest: Option<(Idx, Ordering)>,
+}
+
+// what remove_reporting took out, and the predecessor moved into its place when the
+// removed node had two children
+#[derive(Debug, PartialEq, Eq)]
+pub struct RemovalInfo<'a, T> {
+    pub removed: T,
+    pub promoted: Option<&'a T>,
 }
 
-enum Slot<T> {
-    Occupied { node: Node<T> },
-    Free { next_free: Option<usize> },
+#[derive(Clone)]
+enum Slot<T, Idx> {
+    Occupied { node: Node<T, Idx> },
+    Free { next_free: Option<Idx> },
 }
 
-pub struct IntoIter<T: Ord> {
+pub struct IntoIter<T: Ord, Idx: Index = usize> {
+    tree: Tree<T, Idx>,
+}
+
+// the tree is emptied up front, so dropping a partly consumed Drain still leaves it empty
+pub struct Drain<'a, T: Ord, Idx: Index = usize> {
+    values: std::vec::IntoIter<T>,
+    _tree: &'a mut Tree<T, Idx>,
+}
+
+pub struct Iter<'a, T, Idx: Index = usize> {
+    tree: &'a Tree<T, Idx>,
+    next: Option<Idx>,
+    remaining: usize,
+    #[cfg(debug_assertions)]
+    mod_count: u64,
+}
+
+// descending counterpart of Iter
+pub struct RevIter<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a Tree<T, Idx>,
+    next: Option<Idx>,
+    remaining: usize,
+    #[cfg(debug_assertions)]
+    mod_count: u64,
+}
+
+pub struct Range<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a Tree<T, Idx>,
+    next: Option<Idx>,
+    last: Option<Idx>,
+}
+
+// values may be changed in place, but must keep their order relative to every
+// other value in the tree, inside the range and out; for_each_in_range_mut checks this
+// in debug builds
+pub struct RangeMut<'a, T: Ord, Idx: Index = usize> {
+    // taken once, so handing out one value never reborrows the slots holding the others
+    slots: *mut Slot<T, Idx>,
+    slot_count: usize,
+    // nodes still to come whose left subtrees are done, the next one on top; a node is
+    // only read before its value is handed out, never after
+    stack: Vec<Idx>,
+    last: Option<Idx>,
+    _tree: PhantomData<&'a mut Tree<T, Idx>>,
+}
+
+pub struct PreorderIter<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a Tree<T, Idx>,
+    stack: Vec<Idx>,
+    remaining: usize,
+}
+
+pub struct PostorderIter<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a Tree<T, Idx>,
+    stack: Vec<(Idx, bool)>,
+    remaining: usize,
+}
+
+pub struct LevelIter<'a, T: Ord, Idx: Index = usize> {
+    tree: &'a Tree<T, Idx>,
+    queue: VecDeque<(Idx, usize)>,
+    remaining: usize,
+}
+
+// read-only view of a tree that can be shared between threads, e.g. behind an Arc
+pub struct FrozenTree<T: Ord, Idx: Index = usize> {
+    tree: Tree<T, Idx>,
+}
+
+// multiset on top of the arena tree, equal values share a node that counts them
+pub struct MultiTree<T: Ord> {
+    tree: Tree<Counted<T>>,
+    len: usize,
+}
+
+struct Counted<T> {
+    value: T,
+    count: usize,
+}
+
+// ordered by a closure instead of T's Ord, e.g. descending or by one field of a struct
+pub struct TreeBy<T, F: Fn(&T, &T) -> Ordering> {
     tree: Tree<T>,
+    cmp: F,
+}
+
+// Many small trees sharing one arena and free list. The shared Tree only ever has one of
+// them plugged in as its root at a time, so none of the whole-arena operations (drain,
+// rebuild, shrinking) are reachable through it, and its invariant check skips the
+// free list accounting.
+pub struct TreeArena<T: Ord> {
+    shared: Tree<T>,
+    roots: Vec<ArenaRoot>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TreeId(usize);
+
+#[derive(Clone, Copy)]
+struct ArenaRoot {
+    root: Option<usize>,
+    len: usize,
 }
 
-pub struct Iter<'a, T: Ord> {
-    tree: &'a Tree<T>,
-    next: Option<usize>,
+pub struct MergeIter<'a, T: Ord, Idx: Index = usize> {
+    iters: Vec<Iter<'a, T, Idx>>,
+    heads: HeapQ<Reverse<(&'a T, usize)>>,
+    remaining: usize,
 }
 
-impl<T: Ord> TreeOps<T> for Tree<T> {
+impl<T: Ord, Idx: Index> TreeOps<T> for Tree<T, Idx> {
     fn insert(&mut self, value: T) -> bool {
         let closest = self.find_closest(&value);
-        if let Some(index) = closest {
-            let node = self.unwrap_occupied(index);
-            match value.cmp(&node.value) {
-                Ordering::Equal => return false,
-                ord => {
-                    let new = self.insert_node(value, Some(index));
-                    let node = self.unwrap_occupied_mut(index);
-                    if ord == Ordering::Less {
-                        node.left = Some(new);
-                    } else {
-                        node.right = Some(new);
-                    }
-                    self.update_ancestor_heights(closest);
-                    self.rebalance_ancestors(closest);
-                }
-            }
-        } else {
-            self.root = Some(self.insert_node(value, None));
-        }
-        self.len += 1;
-        true
+        self.insert_at(value, closest).1
     }
 
     fn remove(&mut self, value: &T) -> bool {
-        let closest = self.find_closest(&value);
-        let Some(index) = closest else {
+        let Some((index, Ordering::Equal)) = self.find_closest(value) else {
             return false;
         };
 
-        let node = self.unwrap_occupied(index);
-        if value.cmp(&node.value) != Ordering::Equal {
-            return false;
-        }
-
         self.remove_node(index);
         true
     }
 
     fn contains(&self, value: &T) -> bool {
-        let closest = self.find_closest(value);
-        if let Some(index) = closest {
-            let node = self.unwrap_occupied(index);
-            value.cmp(&node.value) == Ordering::Equal
-        } else {
-            false
-        }
+        matches!(self.find_closest(value), Some((_, Ordering::Equal)))
     }
 
     fn len(&self) -> usize {
         self.len
     }
+
+    fn height(&self) -> i32 {
+        self.height()
+    }
+
+    fn is_balanced(&self) -> bool {
+        self.check_subtree(self.root, true).is_ok()
+    }
+
+    fn boxed_iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.iter())
+    }
+
+    // O(log n) instead of the default's in-order scan
+    fn any_in_bounds(&self, lo: &T, hi: &T) -> bool {
+        self.any_in_range((Bound::Included(lo), Bound::Included(hi)))
+    }
 }
 
+// constructors live on the default index type, like HashMap::new on the default hasher,
+// so Tree::new() needs no annotations; other index types start from Tree::default()
 impl<T: Ord> Tree<T> {
     pub fn new() -> Self {
-        Tree {
-            items: Vec::new(),
-            head_free: None,
-            root: None,
-            len: 0,
-        }
+        Self::default()
     }
 
-    #[cfg(test)]
-    fn height(&self) -> i32 {
-        match self.root {
-            None => -1,
-            Some(index) => self.unwrap_occupied(index).height,
+    // builds a minimal-height tree without rotations, duplicates keep their first copy
+    pub fn from_sorted(mut values: Vec<T>) -> Self {
+        debug_assert!(values.is_sorted(), "from_sorted expects ascending values");
+        values.dedup();
+        let mut tree = Tree::new();
+        tree.build_from_sorted(values);
+        tree
+    }
+
+    // heap-sorts with HeapQ first, O(n log n) overall
+    pub fn from_unsorted(values: Vec<T>) -> Self {
+        Self::from_sorted(HeapQ::from(values).into())
+    }
+}
+
+impl<T: Ord, Idx: Index> Tree<T, Idx> {
+    // Removes every node comparing Equal and returns how many went. insert never stores two
+    // equal values, so this is 0 or 1 here; MultiTree::remove_all clears all copies.
+    pub fn remove_all(&mut self, value: &T) -> usize {
+        let mut removed = 0;
+        while let Some((index, Ordering::Equal)) = self.find_closest(value) {
+            self.remove_node(index);
+            removed += 1;
         }
+        removed
     }
 
-    pub fn into_iter(self) -> IntoIter<T> {
-        IntoIter { tree: self }
+    // inserts like TreeOps::insert; for a duplicate the ref points at the node already stored
+    pub fn insert_ref(&mut self, value: T) -> NodeRef {
+        let closest = self.find_closest(&value);
+        let (index, _) = self.insert_at(value, closest);
+        self.node_ref(index)
     }
 
-    pub fn iter(&self) -> Iter<'_, T> {
-        Iter {
-            tree: self,
-            next: self.first(),
+    pub fn value_at(&self, node_ref: NodeRef) -> Option<&T> {
+        self.resolve(node_ref).map(|index| &self.node(index).value)
+    }
+
+    // None when the ref is stale
+    pub fn remove_ref(&mut self, node_ref: NodeRef) -> Option<T> {
+        let index = self.resolve(node_ref)?;
+        Some(self.remove_node(index))
+    }
+
+    fn node_ref(&self, index: Idx) -> NodeRef {
+        NodeRef {
+            index: index.to_usize(),
+            generation: self.node(index).generation,
         }
     }
 
-    fn find_closest(&self, value: &T) -> Option<usize> {
-        let mut prev = None;
-        let mut cur = self.root;
-        while let Some(index) = cur {
-            prev = cur;
-            let node = self.unwrap_occupied(index);
-            match value.cmp(&node.value) {
-                Ordering::Less => cur = node.left,
-                Ordering::Greater => cur = node.right,
-                Ordering::Equal => return cur,
+    fn resolve(&self, node_ref: NodeRef) -> Option<Idx> {
+        match self.items.get(node_ref.index) {
+            Some(Slot::Occupied { node }) if node.generation == node_ref.generation => {
+                Some(Idx::from_usize(node_ref.index))
             }
+            _ => None,
         }
-        return prev;
     }
 
-    fn insert_node(&mut self, value: T, parent: Option<usize>) -> usize {
-        let mut node = Node::new(value);
-        node.parent = parent;
+    // set-style names for iter and into_iter
+    pub fn values(&self) -> Iter<'_, T, Idx> {
+        self.iter()
+    }
 
-        if let Some(free_index) = self.head_free {
-            if let Slot::Free { next_free } = self.items[free_index] {
-                self.head_free = next_free;
-                self.items[free_index] = Slot::Occupied { node };
-                free_index
-            } else {
-                unreachable!("Corrupted arena");
+    pub fn into_values(self) -> IntoIter<T, Idx> {
+        self.into_iter()
+    }
+
+    // in order, each value with its distance from the root; the stack holds the path of
+    // nodes whose right subtrees are still to come, so it never outgrows the height
+    pub fn iter_with_depth(&self) -> impl Iterator<Item = (usize, &T)> {
+        let mut stack: Vec<(Idx, usize)> = Vec::new();
+        let mut cur = self.root.map(|index| (index, 0));
+        std::iter::from_fn(move || {
+            while let Some((index, depth)) = cur {
+                stack.push((index, depth));
+                cur = self.node(index).left.map(|left| (left, depth + 1));
             }
-        } else {
-            self.items.push(Slot::Occupied { node });
-            self.items.len() - 1
+            let (index, depth) = stack.pop()?;
+            let node = self.node(index);
+            cur = node.right.map(|right| (right, depth + 1));
+            Some((depth, &node.value))
+        })
+    }
+
+    pub fn iter_rev(&self) -> RevIter<'_, T, Idx> {
+        RevIter {
+            tree: self,
+            next: self.last(),
+            remaining: self.len,
+            #[cfg(debug_assertions)]
+            mod_count: self.mod_count,
         }
     }
 
-    fn remove_node(&mut self, index: usize) -> T {
-        let (node_left, node_right, node_parent) = {
-            let node = self.unwrap_occupied(index);
-            (node.left, node.right, node.parent)
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T, Idx> {
+        let (next, last) = self.range_indices(range);
+        Range {
+            tree: self,
+            next,
+            last,
+        }
+    }
+
+    // owned copies in sorted order; a Range can't count its values without walking them,
+    // so the vec is not pre-sized
+    pub fn range_to_vec<R: RangeBounds<T>>(&self, range: R) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.range(range).cloned().collect()
+    }
+
+    // resumes a scan at the first value >= start in O(log n); returns a Range rather than an
+    // Iter because the count of values left is unknown without walking them
+    pub fn iter_from(&self, start: &T) -> Range<'_, T, Idx> {
+        self.range((Bound::Included(start), Bound::Unbounded))
+    }
+
+    // starts at the first value > start, for resuming after the last key already seen
+    pub fn iter_from_exclusive(&self, start: &T) -> Range<'_, T, Idx> {
+        self.range((Bound::Excluded(start), Bound::Unbounded))
+    }
+
+    // O(log n), checks the first value past the start bound against the end bound
+    pub fn any_in_range<R: RangeBounds<T>>(&self, range: R) -> bool {
+        let Some(first) = self.lower_bound(range.start_bound()) else {
+            return false;
         };
+        let value = &self.node(first).value;
+        match range.end_bound() {
+            Bound::Included(end) => value <= end,
+            Bound::Excluded(end) => value < end,
+            Bound::Unbounded => true,
+        }
+    }
 
-        if let (Some(_), Some(_)) = (node_left, node_right) {
-            let before = self.before_sub(index);
-            let before_index = before.expect("Node with left child should have before node");
-            let before_node = self.unwrap_occupied(before_index);
+    pub fn range_mut<R: RangeBounds<T>>(&mut self, range: R) -> RangeMut<'_, T, Idx> {
+        let (next, last) = self.range_indices(range);
+        // the first node, then every ancestor it sits left of, those follow it in order
+        let mut stack: Vec<Idx> = next.into_iter().collect();
+        let mut cur = next;
+        while let Some(index) = cur {
+            let parent = self.node(index).parent;
+            if let Some(parent_index) = parent
+                && self.node(parent_index).left == cur
+            {
+                stack.push(parent_index);
+            }
+            cur = parent;
+        }
+        stack.reverse();
+        RangeMut {
+            slots: self.items.as_mut_ptr(),
+            slot_count: self.items.len(),
+            stack,
+            last,
+            _tree: PhantomData,
+        }
+    }
 
-            let ancestor_start = if before == node_left {
-                before
+    // like range_mut, but debug builds check each changed value against its neighbours
+    // once f returns, so a broken order is caught at the value that broke it
+    pub fn for_each_in_range_mut<R, F>(&mut self, range: R, mut f: F)
+    where
+        R: RangeBounds<T>,
+        F: FnMut(&mut T),
+    {
+        let (mut next, last) = self.range_indices(range);
+        while let Some(index) = next {
+            next = if next == last {
+                None
             } else {
-                before_node.parent
+                self.after(index)
             };
+            f(&mut self.node_mut(index).value);
+            self.debug_check_order(index, "for_each_in_range_mut");
+        }
+    }
 
-            self.replace_node(before_index, before_node.left);
-            self.replace_node(index, before);
+    pub fn get(&self, value: &T) -> Option<&T> {
+        match self.find_closest(value) {
+            Some((index, Ordering::Equal)) => Some(&self.node(index).value),
+            _ => None,
+        }
+    }
 
-            self.update_ancestor_heights(ancestor_start);
-            self.rebalance_ancestors(ancestor_start);
-        } else {
-            let child = node_left.or(node_right);
-            self.replace_node(index, child);
+    pub fn into_frozen(self) -> FrozenTree<T, Idx> {
+        FrozenTree { tree: self }
+    }
 
-            self.update_ancestor_heights(node_parent);
-            self.rebalance_ancestors(node_parent);
+    pub fn iter_preorder(&self) -> PreorderIter<'_, T, Idx> {
+        PreorderIter {
+            tree: self,
+            stack: self.root.into_iter().collect(),
+            remaining: self.len,
         }
+    }
 
-        let value = self.remove_node_from_arena(index);
-        self.len -= 1;
-        value
+    pub fn iter_postorder(&self) -> PostorderIter<'_, T, Idx> {
+        PostorderIter {
+            tree: self,
+            stack: self.root.map(|index| (index, false)).into_iter().collect(),
+            remaining: self.len,
+        }
     }
 
-    fn replace_node(&mut self, node_index: usize, new_link: Option<usize>) {
-        let (node_parent, node_left, node_right) = {
-            let node = self.unwrap_occupied(node_index);
-            (node.parent, node.left, node.right)
-        };
-        if let Some(parent_index) = node_parent {
-            let parent_node_left = self.unwrap_occupied(parent_index).left;
-            if parent_node_left == Some(node_index) {
-                self.with_occupied_mut(parent_index, |parent| parent.left = new_link);
-            } else {
-                self.with_occupied_mut(parent_index, |parent| parent.right = new_link);
-            }
-        } else {
-            self.root = new_link;
+    // yields (depth, value) pairs, the root being at depth 0
+    pub fn iter_levels(&self) -> LevelIter<'_, T, Idx> {
+        LevelIter {
+            tree: self,
+            queue: self.root.map(|index| (index, 0)).into_iter().collect(),
+            remaining: self.len,
         }
+    }
 
-        if let Some(new_index) = new_link {
-            self.with_occupied_mut(new_index, |new_node| new_node.parent = node_parent);
+    // Climbs from the hint's node until ancestors on both sides of value have been seen, then
+    // descends from there, so values close to the hint are placed after visiting only a few
+    // nodes. Returns the value's node, to hint the next insert with, and whether it was new.
+    // A stale hint falls back to a search from the root.
+    pub fn insert_after_hint(&mut self, value: T, hint: NodeRef) -> (NodeRef, bool) {
+        let Some(hint_index) = self.resolve(hint) else {
+            let closest = self.find_closest(&value);
+            let (index, new) = self.insert_at(value, closest);
+            return (self.node_ref(index), new);
+        };
 
-            if node_left != new_link {
-                self.with_occupied_mut(new_index, |new_node| new_node.left = node_left);
-                if let Some(child_index) = self.unwrap_occupied(new_index).left {
-                    self.with_occupied_mut(child_index, |child| child.parent = new_link);
+        let mut cur = hint_index;
+        let mut below = false;
+        let mut above = false;
+        loop {
+            match value.cmp(&self.node(cur).value) {
+                Ordering::Equal => {
+                    let (index, new) = self.insert_at(value, Some((cur, Ordering::Equal)));
+                    return (self.node_ref(index), new);
                 }
+                Ordering::Less => above = true,
+                Ordering::Greater => below = true,
             }
-
-            if node_right != new_link {
-                self.with_occupied_mut(new_index, |new_node| new_node.right = node_right);
-                if let Some(child_index) = self.unwrap_occupied(new_index).right {
-                    self.with_occupied_mut(child_index, |child| child.parent = new_link);
-                }
+            match self.node(cur).parent {
+                Some(parent) if !(below && above) => cur = parent,
+                _ => break,
             }
         }
+
+        let closest = self.find_closest_from(Some(cur), |node_value| value.cmp(node_value));
+        let (index, new) = self.insert_at(value, closest);
+        (self.node_ref(index), new)
     }
 
-    fn remove_node_from_arena(&mut self, index: usize) -> T {
-        let slot = replace(
-            &mut self.items[index],
-            Slot::Free {
-                next_free: self.head_free,
-            },
-        );
-        self.head_free = Some(index);
-        let Slot::Occupied { node } = slot else {
-            unreachable!("Corrupted arena");
-        };
-        node.value
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
     }
 
-    fn first(&self) -> Option<usize> {
-        let mut cur = self.root;
-        while let Some(cur_index) = cur {
-            match self.before(cur_index) {
-                None => return cur,
-                before => cur = before,
-            }
+    // hands the value back instead of aborting when the arena can't grow
+    pub fn try_insert(&mut self, value: T) -> Result<bool, T> {
+        if self.head_free.is_none() && self.items.try_reserve(1).is_err() {
+            return Err(value);
         }
-        None
+        Ok(self.insert(value))
     }
 
-    fn rebalance_ancestors(&mut self, link: Option<usize>) {
-        let mut cur = link;
+    #[cfg(feature = "stats")]
+    pub fn rotation_count(&self) -> u64 {
+        self.rotation_count
+    }
+
+    // the arena only grows when the free list is empty, following Vec's own doubling policy,
+    // and it never shrinks on removal
+    pub fn capacity(&self) -> usize {
+        self.items.capacity()
+    }
+
+    // O(number of free slots), walks the free list
+    pub fn free_slots(&self) -> usize {
+        let mut count = 0;
+        let mut cur = self.head_free;
         while let Some(index) = cur {
-            self.rebalance(cur);
-            cur = self.unwrap_occupied(index).parent;
+            cur = self.next_free(index);
+            count += 1;
         }
+        count
     }
 
-    fn rebalance(&mut self, link: Option<usize>) {
-        let Some(index) = link else {
-            return;
-        };
-        let node = self.unwrap_occupied(index);
-        let balance_factor = self.balance_factor(link);
-        if balance_factor > 1 {
-            let mut height_start = link;
-            if self.balance_factor(node.left) < 0 {
-                height_start = node.left;
-                self.rotate_left(node.left);
-            }
-            self.rotate_right(link);
-            self.update_ancestor_heights(height_start);
-        } else if balance_factor < -1 {
-            let mut height_start = link;
-            if self.balance_factor(node.right) > 0 {
-                height_start = node.right;
-                self.rotate_right(node.right);
-            }
-            self.rotate_left(link);
-            self.update_ancestor_heights(height_start);
+    // free share of the arena's slots, what the shrink policy is measured against; 0.0 for
+    // an empty arena
+    pub fn fragmentation(&self) -> f64 {
+        if self.items.is_empty() {
+            return 0.0;
         }
+        (self.items.len() - self.len) as f64 / self.items.len() as f64
     }
 
-    fn rotate_right(&mut self, x_link: Option<usize>) -> Option<usize> {
-        let Some(x_index) = x_link else {
-            return None;
-        };
-        let (x_left, x_parent) = {
-            let x = self.unwrap_occupied(x_index);
-            (x.left, x.parent)
-        };
-        let y_link = x_left;
-        let Some(y_index) = y_link else {
-            return None;
+    // compacts the arena down to exactly len slots in O(n); NodeRefs go stale
+    pub fn shrink_to_fit(&mut self) {
+        self.compact();
+    }
+
+    // removes and returns the smallest value; like every removal, compacts the arena when
+    // the shrink policy says so
+    pub fn take_min(&mut self) -> Option<T> {
+        self.first().map(|index| self.remove_node(index))
+    }
+
+    pub fn take_max(&mut self) -> Option<T> {
+        self.last().map(|index| self.remove_node(index))
+    }
+
+    // take_min/take_max under BTreeSet's names
+    pub fn pop_first(&mut self) -> Option<T> {
+        self.take_min()
+    }
+
+    pub fn pop_last(&mut self) -> Option<T> {
+        self.take_max()
+    }
+
+    // Fragmentation above which any removal compacts the arena. Compaction rebuilds in O(n)
+    // and moves nodes, so every NodeRef goes stale. None, the default, never compacts.
+    pub fn set_shrink_policy(&mut self, threshold: Option<f64>) {
+        if let Some(threshold) = threshold {
+            assert!(
+                threshold > 0.0 && threshold <= 1.0,
+                "Shrink threshold must be in (0, 1], got {threshold}"
+            );
+        }
+        self.shrink_threshold = threshold;
+    }
+
+    // With rebalancing off, insert and remove only keep heights up to date, turning the
+    // tree into a plain BST that degrades on sorted input. For demonstration only:
+    // is_balanced reports the imbalance. Bulk builders such as rebuild still balance.
+    pub fn set_rebalance(&mut self, enabled: bool) {
+        self.rebalance = enabled;
+    }
+
+    // applies f in sorted order; outputs that are still sorted take the O(n) builder,
+    // anything else is inserted one by one, and values mapping to equal results keep the first
+    pub fn map<U: Ord, F: FnMut(&T) -> U>(&self, f: F) -> Tree<U> {
+        let values: Vec<U> = self.iter().map(f).collect();
+        if values.is_sorted() {
+            Tree::from_sorted(values)
+        } else {
+            let mut tree = Tree::new();
+            tree.extend(values);
+            tree
+        }
+    }
+
+    // f must be monotonic non-decreasing, checked in debug builds only
+    pub fn map_sorted<U: Ord, F: FnMut(&T) -> U>(&self, f: F) -> Tree<U> {
+        Tree::from_sorted(self.iter().map(f).collect())
+    }
+
+    pub fn to_sorted_vec(&self) -> Vec<&T> {
+        let mut values = Vec::with_capacity(self.len);
+        values.extend(self.iter());
+        values
+    }
+
+    // walks the arena in order once, without the rebalancing IntoIter does per value
+    pub fn into_sorted_vec(mut self) -> Vec<T> {
+        self.take_sorted()
+    }
+
+    pub fn first_value(&self) -> Option<&T> {
+        self.first().map(|index| &self.node(index).value)
+    }
+
+    pub fn last_value(&self) -> Option<&T> {
+        self.last().map(|index| &self.node(index).value)
+    }
+
+    // (min, max), a single node tree returns the root for both
+    pub fn bounds(&self) -> Option<(&T, &T)> {
+        let first = self.first()?;
+        let last = if self.len == 1 { first } else { self.last()? };
+        Some((&self.node(first).value, &self.node(last).value))
+    }
+
+    // O(k), walks the in-order iterator rather than using subtree sizes
+    pub fn nth(&self, k: usize) -> Option<&T> {
+        self.iter().nth(k)
+    }
+
+    // moves all of other's values into self, leaving other empty; on duplicates self's copy is kept
+    pub fn append(&mut self, other: &mut Tree<T, Idx>) {
+        let mut mine = self.take_sorted().into_iter().peekable();
+        let mut theirs = other.take_sorted().into_iter().peekable();
+        let mut merged = Vec::with_capacity(mine.len() + theirs.len());
+        loop {
+            let next = match (mine.peek(), theirs.peek()) {
+                (Some(a), Some(b)) => match a.cmp(b) {
+                    Ordering::Less => mine.next(),
+                    Ordering::Greater => theirs.next(),
+                    Ordering::Equal => {
+                        theirs.next();
+                        mine.next()
+                    }
+                },
+                (Some(_), None) => mine.next(),
+                (None, Some(_)) => theirs.next(),
+                (None, None) => break,
+            };
+            merged.extend(next);
+        }
+        self.build_from_sorted(merged);
+    }
+
+    // keeps the k smallest values and returns the rest, with the same policies, as a new tree;
+    // nodes carry no subtree sizes, so both halves are rebuilt from the sorted values in O(n)
+    pub fn split_at_rank(&mut self, k: usize) -> Tree<T, Idx> {
+        let mut lower = self.take_sorted();
+        let upper = lower.split_off(k.min(lower.len()));
+        self.build_from_sorted(lower);
+
+        let mut rest = Tree {
+            duplicate_policy: self.duplicate_policy,
+            shrink_threshold: self.shrink_threshold,
+            rebalance: self.rebalance,
+            ..Tree::default()
+        };
+        rest.build_from_sorted(upper);
+        rest
+    }
+
+    // (largest value <= value, smallest value >= value), both are the match when present
+    pub fn floor_ceiling(&self, value: &T) -> (Option<&T>, Option<&T>) {
+        let (floor, ceiling) = match self.find_closest(value) {
+            None => (None, None),
+            Some((index, Ordering::Equal)) => (Some(index), Some(index)),
+            Some((index, Ordering::Less)) => (self.before(index), Some(index)),
+            Some((index, Ordering::Greater)) => (Some(index), self.after(index)),
         };
-        let t2_link = self.unwrap_occupied(y_index).right;
+        let value_at = |index: Idx| &self.node(index).value;
+        (floor.map(value_at), ceiling.map(value_at))
+    }
 
-        // fix parent -> y
-        if let Some(parent_index) = x_parent {
-            let parent_node_right = self.unwrap_occupied(parent_index).right;
-            if parent_node_right == x_link {
-                self.with_occupied_mut(parent_index, |parent| parent.right = y_link);
+    // first value for which pred holds, assuming pred is false for a prefix of the values
+    // and true for the rest; one descent, like lower_bound with a predicate for the bound
+    pub fn partition_point<P: FnMut(&T) -> bool>(&self, mut pred: P) -> Option<&T> {
+        let mut result = None;
+        let mut cur = self.root;
+        while let Some(index) = cur {
+            let node = self.node(index);
+            if pred(&node.value) {
+                result = Some(&node.value);
+                cur = node.left;
             } else {
-                self.with_occupied_mut(parent_index, |parent| parent.left = y_link);
+                cur = node.right;
             }
-        } else {
-            self.root = y_link;
         }
+        result
+    }
 
-        self.with_occupied_mut(y_index, |y| y.parent = x_parent);
+    // one descent plus a step to each neighbour, instead of separate contains/floor/ceiling calls
+    pub fn locate(&self, value: &T) -> Located<'_, T> {
+        let (exists, prev, next) = match self.find_closest(value) {
+            None => (false, None, None),
+            Some((index, Ordering::Equal)) => (true, self.before(index), self.after(index)),
+            Some((index, Ordering::Less)) => (false, self.before(index), Some(index)),
+            Some((index, Ordering::Greater)) => (false, Some(index), self.after(index)),
+        };
+        let value_at = |index: Idx| &self.node(index).value;
+        Located {
+            exists,
+            prev: prev.map(value_at),
+            next: next.map(value_at),
+        }
+    }
 
-        // fix y -> x
-        self.with_occupied_mut(x_index, |x| x.parent = y_link);
-        self.with_occupied_mut(y_index, |y| y.right = x_link);
+    // edges from the root down to value, None when absent
+    pub fn depth_of(&self, value: &T) -> Option<usize> {
+        let mut depth = 0;
+        let mut cur = self.root;
+        while let Some(index) = cur {
+            let node = self.node(index);
+            cur = match value.cmp(&node.value) {
+                Ordering::Less => node.left,
+                Ordering::Greater => node.right,
+                Ordering::Equal => return Some(depth),
+            };
+            depth += 1;
+        }
+        None
+    }
 
-        // fix x -> t2
-        self.with_occupied_mut(x_index, |x| x.left = t2_link);
-        if let Some(t2_index) = t2_link {
-            self.with_occupied_mut(t2_index, |t2| t2.parent = x_link);
+    // relinks the tree at minimal height in O(n), reusing the arena's allocation
+    pub fn rebuild(&mut self) {
+        let values = self.take_sorted();
+        self.build_from_sorted(values);
+    }
+
+    // sorted values out in O(n), the arena keeps its capacity for reuse
+    pub fn drain(&mut self) -> Drain<'_, T, Idx> {
+        Drain {
+            values: self.take_sorted().into_iter(),
+            _tree: self,
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, slice: &[T])
+    where
+        T: Clone,
+    {
+        for value in slice {
+            let _ = self.insert(value.clone());
+        }
+    }
+
+    // on an occupied entry the passed value is dropped and the stored one kept
+    pub fn entry(&mut self, value: T) -> Entry<'_, T, Idx> {
+        match self.find_closest(&value) {
+            Some((index, Ordering::Equal)) => Entry::Occupied(OccupiedEntry { tree: self, index }),
+            closest => Entry::Vacant(VacantEntry {
+                tree: self,
+                value,
+                closest,
+            }),
         }
-        y_link
     }
 
-    fn rotate_left(&mut self, x_link: Option<usize>) -> Option<usize> {
-        let Some(x_index) = x_link else {
+    pub fn remove_reporting(&mut self, value: &T) -> Option<RemovalInfo<'_, T>> {
+        let Some((index, Ordering::Equal)) = self.find_closest(value) else {
             return None;
         };
-        let (x_right, x_parent) = {
-            let x = self.unwrap_occupied(x_index);
-            (x.right, x.parent)
+        let node = self.node(index);
+        // remove_node splices in the in-order predecessor only when both children exist;
+        // compaction may move it, so it is found again afterwards as the removed value's floor
+        let two_children = node.left.is_some() && node.right.is_some();
+        let removed = self.remove_node(index);
+        let promoted = if two_children {
+            self.floor_ceiling(&removed).0
+        } else {
+            None
         };
-        let y_link = x_right;
-        let Some(y_index) = y_link else {
+        Some(RemovalInfo { removed, promoted })
+    }
+
+    // one entry per value, in input order: true if it was new, false if already present
+    pub fn insert_reporting(&mut self, values: impl IntoIterator<Item = T>) -> Vec<bool> {
+        values.into_iter().map(|value| self.insert(value)).collect()
+    }
+
+    // the set relations below walk both trees in order once, O(n + m)
+    pub fn is_subset(&self, other: &Tree<T, Idx>) -> bool {
+        if self.len > other.len {
+            return false;
+        }
+        let mut theirs = other.iter();
+        'mine: for value in self.iter() {
+            for candidate in theirs.by_ref() {
+                match value.cmp(candidate) {
+                    Ordering::Less => return false,
+                    Ordering::Equal => continue 'mine,
+                    Ordering::Greater => {}
+                }
+            }
+            return false;
+        }
+        true
+    }
+
+    pub fn is_superset(&self, other: &Tree<T, Idx>) -> bool {
+        other.is_subset(self)
+    }
+
+    pub fn is_disjoint(&self, other: &Tree<T, Idx>) -> bool {
+        let mut mine = self.iter().peekable();
+        let mut theirs = other.iter().peekable();
+        while let (Some(a), Some(b)) = (mine.peek(), theirs.peek()) {
+            match a.cmp(b) {
+                Ordering::Less => {
+                    mine.next();
+                }
+                Ordering::Greater => {
+                    theirs.next();
+                }
+                Ordering::Equal => return false,
+            }
+        }
+        true
+    }
+
+    // O(capacity) scan of the arena, only the parent links are checked
+    pub fn verify_parents(&self) -> bool {
+        if let Some(root) = self.root
+            && !matches!(self.items.get(root.to_usize()), Some(Slot::Occupied { node }) if node.parent.is_none())
+        {
+            return false;
+        }
+        self.items.iter().enumerate().all(|(index, slot)| {
+            let Slot::Occupied { node } = slot else {
+                return true;
+            };
+            [node.left, node.right].into_iter().flatten().all(|child| {
+                matches!(self.items.get(child.to_usize()), Some(Slot::Occupied { node: child }) if child.parent == Some(Idx::from_usize(index)))
+            })
+        })
+    }
+
+    pub fn check_invariants(&self) -> Result<(), String> {
+        self.check_invariants_by(T::cmp)
+    }
+
+    // the checked feature validates the whole tree on the way out of every insert and
+    // removal, and every removal applies the shrink policy
+    fn insert_at(&mut self, value: T, closest: Option<(Idx, Ordering)>) -> (Idx, bool) {
+        let inserted = self.link_value(value, closest);
+        #[cfg(feature = "checked")]
+        self.assert_invariants_by("insert", T::cmp);
+        inserted
+    }
+
+    fn remove_node(&mut self, index: Idx) -> T {
+        let value = self.unlink_node(index);
+        self.maybe_compact();
+        #[cfg(feature = "checked")]
+        self.assert_invariants_by("remove", T::cmp);
+        value
+    }
+
+    // first and last index inside the range, next is None when the range is empty
+    fn range_indices<R: RangeBounds<T>>(&self, range: R) -> (Option<Idx>, Option<Idx>) {
+        let first = self.lower_bound(range.start_bound());
+        let last = self.upper_bound(range.end_bound());
+        let next = match (first, last) {
+            (Some(first_index), Some(last_index))
+                if self.node(first_index).value <= self.node(last_index).value =>
+            {
+                first
+            }
+            _ => None,
+        };
+        (next, last)
+    }
+
+    // first node that is within the start bound
+    fn lower_bound(&self, bound: Bound<&T>) -> Option<Idx> {
+        let mut result = None;
+        let mut cur = self.root;
+        while let Some(index) = cur {
+            let node = self.node(index);
+            let within = match bound {
+                Bound::Included(start) => node.value >= *start,
+                Bound::Excluded(start) => node.value > *start,
+                Bound::Unbounded => true,
+            };
+            if within {
+                result = cur;
+                cur = node.left;
+            } else {
+                cur = node.right;
+            }
+        }
+        result
+    }
+
+    // last node that is within the end bound
+    fn upper_bound(&self, bound: Bound<&T>) -> Option<Idx> {
+        let mut result = None;
+        let mut cur = self.root;
+        while let Some(index) = cur {
+            let node = self.node(index);
+            let within = match bound {
+                Bound::Included(end) => node.value <= *end,
+                Bound::Excluded(end) => node.value < *end,
+                Bound::Unbounded => true,
+            };
+            if within {
+                result = cur;
+                cur = node.right;
+            } else {
+                cur = node.left;
+            }
+        }
+        result
+    }
+
+    // returns the last visited node and how value compares to it
+    fn find_closest(&self, value: &T) -> Option<(Idx, Ordering)> {
+        self.find_closest_by(|node_value| value.cmp(node_value))
+    }
+
+    // never on a shared arena, whose slots also hold other trees
+    fn maybe_compact(&mut self) {
+        if let Some(threshold) = self.shrink_threshold
+            && !self.shares_arena
+            && self.fragmentation() > threshold
+        {
+            self.compact();
+        }
+    }
+
+    // rebuilds the tree into a dense, minimal arena in O(n)
+    fn compact(&mut self) {
+        let values = self.take_sorted();
+        self.items.shrink_to(values.len());
+        self.build_from_sorted(values);
+    }
+
+    // empties the tree in O(n) without rebalancing, keeping the arena's capacity
+    fn take_sorted(&mut self) -> Vec<T> {
+        let mut order = Vec::with_capacity(self.len);
+        let mut cur = self.first();
+        while let Some(index) = cur {
+            order.push(index);
+            cur = self.after(index);
+        }
+
+        let mut values: Vec<Option<T>> = self
+            .items
+            .drain(..)
+            .map(|slot| match slot {
+                Slot::Occupied { node } => Some(node.value),
+                Slot::Free { .. } => None,
+            })
+            .collect();
+        self.head_free = None;
+        self.root = None;
+        self.len = 0;
+        self.record_modification();
+
+        order
+            .into_iter()
+            .map(|index| {
+                values[index.to_usize()]
+                    .take()
+                    .expect("In-order index should be occupied")
+            })
+            .collect()
+    }
+
+    // expects an empty tree and strictly ascending values
+    fn build_from_sorted(&mut self, values: Vec<T>) {
+        debug_assert!(self.root.is_none() && self.items.is_empty());
+        self.len = values.len();
+        for value in values {
+            let generation = self.bump_generation();
+            self.items.push(Slot::Occupied {
+                node: Node::new(value, generation),
+            });
+        }
+        self.root = self.link_balanced(0, self.len, None);
+        #[cfg(feature = "checked")]
+        self.assert_invariants_by("rebuild", T::cmp);
+    }
+
+    // links the already sorted arena range [lo, hi) into a subtree of minimal height
+    fn link_balanced(&mut self, lo: usize, hi: usize, parent: Option<Idx>) -> Option<Idx> {
+        if lo >= hi {
             return None;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let mid_index = Idx::from_usize(mid);
+        let left = self.link_balanced(lo, mid, Some(mid_index));
+        let right = self.link_balanced(mid + 1, hi, Some(mid_index));
+        let height = 1 + self.link_height(left).max(self.link_height(right));
+        let node = self.node_mut(mid_index);
+        node.parent = parent;
+        node.left = left;
+        node.right = right;
+        node.height = height;
+        Some(mid_index)
+    }
+
+    // O(log n): only the neighbours can have been overtaken
+    fn debug_check_order(&self, index: Idx, changed_through: &str) {
+        if cfg!(debug_assertions) {
+            let value = &self.node(index).value;
+            let before = self.before(index);
+            let after = self.after(index);
+            assert!(
+                before.is_none_or(|before| self.node(before).value < *value)
+                    && after.is_none_or(|after| *value < self.node(after).value),
+                "Value changed through {changed_through} broke the tree order"
+            );
+        }
+    }
+
+    #[cfg(test)]
+    fn set_head_free(&mut self, head_free: Option<Idx>) {
+        self.head_free = head_free;
+    }
+}
+
+// the arena and AVL structure: nothing here compares values, so TreeBy can store its
+// values directly and search them with its own comparator
+impl<T, Idx: Index> Tree<T, Idx> {
+    // links value below the node returned by find_closest, returns where the value is stored
+    // and whether it is new; rotations relink nodes but never move them between slots
+    fn link_value(&mut self, value: T, closest: Option<(Idx, Ordering)>) -> (Idx, bool) {
+        let new = match closest {
+            None => {
+                let new = self.insert_node(value, None);
+                self.root = Some(new);
+                new
+            }
+            Some((index, Ordering::Equal)) => {
+                if self.duplicate_policy == DuplicatePolicy::Replace {
+                    self.node_mut(index).value = value;
+                }
+                return (index, false);
+            }
+            Some((index, ord)) => {
+                let new = self.insert_node(value, Some(index));
+                let node = self.node_mut(index);
+                if ord == Ordering::Less {
+                    node.left = Some(new);
+                } else {
+                    node.right = Some(new);
+                }
+                self.retrace_insert(Some(index));
+                new
+            }
         };
-        let t2_link = self.unwrap_occupied(y_index).left;
+        self.len += 1;
+        self.record_modification();
+        (new, true)
+    }
 
-        // fix parent -> y
-        if let Some(parent_index) = x_parent {
-            let parent_node_left = self.unwrap_occupied(parent_index).left;
-            if parent_node_left == x_link {
-                self.with_occupied_mut(parent_index, |parent| parent.left = y_link);
+    // edges on the longest path from the root to a leaf, -1 for an empty tree
+    pub fn height(&self) -> i32 {
+        match self.root {
+            None => -1,
+            Some(index) => self.node(index).height,
+        }
+    }
+
+    // inherent copies of the TreeOps methods, so callers don't need the trait in scope
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, Idx> {
+        Iter {
+            tree: self,
+            next: self.first(),
+            remaining: self.len,
+            #[cfg(debug_assertions)]
+            mod_count: self.mod_count,
+        }
+    }
+
+    // like find_closest, but cmp compares the searched for key against a stored value
+    fn find_closest_by<F>(&self, cmp: F) -> Option<(Idx, Ordering)>
+    where
+        F: Fn(&T) -> Ordering,
+    {
+        self.find_closest_from(self.root, cmp)
+    }
+
+    fn find_closest_from<F>(&self, start: Option<Idx>, cmp: F) -> Option<(Idx, Ordering)>
+    where
+        F: Fn(&T) -> Ordering,
+    {
+        let mut prev = None;
+        let mut cur = start;
+        while let Some(index) = cur {
+            let node = self.node(index);
+            let ord = cmp(&node.value);
+            prev = Some((index, ord));
+            match ord {
+                Ordering::Less => cur = node.left,
+                Ordering::Greater => cur = node.right,
+                Ordering::Equal => break,
+            }
+        }
+        prev
+    }
+
+    fn bump_generation(&mut self) -> u64 {
+        self.next_generation += 1;
+        self.next_generation
+    }
+
+    fn insert_node(&mut self, value: T, parent: Option<Idx>) -> Idx {
+        let mut node = Node::new(value, self.bump_generation());
+        node.parent = parent;
+
+        if let Some(free_index) = self.head_free {
+            self.head_free = self.next_free(free_index);
+            debug_assert!(
+                self.head_free != Some(free_index),
+                "Corrupted arena: free slot {free_index} links to itself"
+            );
+            self.items[free_index.to_usize()] = Slot::Occupied { node };
+            free_index
+        } else {
+            let index = Idx::from_usize(self.items.len());
+            self.items.push(Slot::Occupied { node });
+            index
+        }
+    }
+
+    fn unlink_node(&mut self, index: Idx) -> T {
+        self.record_modification();
+        let (node_left, node_right, node_parent) = {
+            let node = self.node(index);
+            (node.left, node.right, node.parent)
+        };
+
+        if let (Some(_), Some(_)) = (node_left, node_right) {
+            let before = self.before_sub(index);
+            let before_index = before.expect("Node with left child should have before node");
+            let before_node = self.node(before_index);
+
+            let ancestor_start = if before == node_left {
+                before
             } else {
-                self.with_occupied_mut(parent_index, |parent| parent.right = y_link);
+                before_node.parent
+            };
+
+            self.replace_node(before_index, before_node.left);
+            self.replace_node(index, before);
+
+            self.update_ancestor_heights(ancestor_start);
+            self.rebalance_ancestors(ancestor_start);
+        } else {
+            let child = node_left.or(node_right);
+            self.replace_node(index, child);
+
+            self.update_ancestor_heights(node_parent);
+            self.rebalance_ancestors(node_parent);
+        }
+
+        let value = self.remove_node_from_arena(index);
+        self.len -= 1;
+        value
+    }
+
+    fn replace_node(&mut self, node_index: Idx, new_link: Option<Idx>) {
+        let (node_parent, node_left, node_right) = {
+            let node = self.node(node_index);
+            (node.parent, node.left, node.right)
+        };
+        if let Some(parent_index) = node_parent {
+            let parent_node_left = self.node(parent_index).left;
+            if parent_node_left == Some(node_index) {
+                self.with_occupied_mut(parent_index, |parent| parent.left = new_link);
+            } else {
+                self.with_occupied_mut(parent_index, |parent| parent.right = new_link);
+            }
+        } else {
+            self.root = new_link;
+        }
+
+        if let Some(new_index) = new_link {
+            self.with_occupied_mut(new_index, |new_node| new_node.parent = node_parent);
+
+            if node_left != new_link {
+                self.with_occupied_mut(new_index, |new_node| new_node.left = node_left);
+                if let Some(child_index) = self.node(new_index).left {
+                    self.with_occupied_mut(child_index, |child| child.parent = new_link);
+                }
+            }
+
+            if node_right != new_link {
+                self.with_occupied_mut(new_index, |new_node| new_node.right = node_right);
+                if let Some(child_index) = self.node(new_index).right {
+                    self.with_occupied_mut(child_index, |child| child.parent = new_link);
+                }
+            }
+        }
+    }
+
+    fn remove_node_from_arena(&mut self, index: Idx) -> T {
+        // checked before the slot joins the free list, which a double free would turn into a cycle
+        debug_assert!(
+            matches!(
+                self.items.get(index.to_usize()),
+                Some(Slot::Occupied { .. })
+            ),
+            "Corrupted arena: freeing slot {index} which is not occupied"
+        );
+        let slot = replace(
+            &mut self.items[index.to_usize()],
+            Slot::Free {
+                next_free: self.head_free,
+            },
+        );
+        self.head_free = Some(index);
+        let Slot::Occupied { node } = slot else {
+            panic!("Corrupted arena: freed slot {index} was not occupied");
+        };
+        node.value
+    }
+
+    fn first(&self) -> Option<Idx> {
+        let mut cur = self.root;
+        while let Some(cur_index) = cur {
+            match self.before(cur_index) {
+                None => return cur,
+                before => cur = before,
+            }
+        }
+        None
+    }
+
+    fn last(&self) -> Option<Idx> {
+        let mut cur = self.root;
+        while let Some(cur_index) = cur {
+            match self.after(cur_index) {
+                None => return cur,
+                after => cur = after,
+            }
+        }
+        None
+    }
+
+    // One bottom-up pass after an insert: each ancestor gets its height updated and is
+    // rebalanced on the spot. The walk stops at the first subtree that is as tall as before,
+    // since nothing above it can have changed. After an insert the first (single or double)
+    // rotation always brings its subtree back to the pre-insert height, so it ends the walk
+    // too; a removal can need a rotation at every level, which is why it doesn't come here.
+    fn retrace_insert(&mut self, link: Option<Idx>) {
+        if !self.rebalance {
+            self.update_ancestor_heights(link);
+            return;
+        }
+        let mut cur = link;
+        while let Some(index) = cur {
+            let old_height = self.node(index).height;
+            self.update_height(index);
+            let subtree = self.rebalance_at(index);
+            if subtree != index {
+                debug_assert_eq!(self.node(subtree).height, old_height);
+                break;
+            }
+            let node = self.node(index);
+            if node.height == old_height {
+                break;
+            }
+            cur = node.parent;
+        }
+    }
+
+    // Rotates the subtree at index back into balance if needed and returns its root. Only
+    // the new root and its children need their heights recomputed, the subtrees below them
+    // moved over whole.
+    fn rebalance_at(&mut self, index: Idx) -> Idx {
+        let balance_factor = self.balance_factor(Some(index));
+        let node = self.node(index);
+        let root = if balance_factor > 1 {
+            if self.balance_factor(node.left) < 0 {
+                self.rotate_left(node.left);
+            }
+            self.rotate_right(Some(index))
+        } else if balance_factor < -1 {
+            if self.balance_factor(node.right) > 0 {
+                self.rotate_right(node.right);
+            }
+            self.rotate_left(Some(index))
+        } else {
+            return index;
+        };
+
+        let root = root.expect("An unbalanced node has a child to rotate up");
+        let root_node = self.node(root);
+        for child in [root_node.left, root_node.right].into_iter().flatten() {
+            self.update_height(child);
+        }
+        self.update_height(root);
+        root
+    }
+
+    fn rebalance_ancestors(&mut self, link: Option<Idx>) {
+        if !self.rebalance {
+            return;
+        }
+        let mut cur = link;
+        while let Some(index) = cur {
+            self.rebalance(cur);
+            cur = self.node(index).parent;
+        }
+    }
+
+    fn rebalance(&mut self, link: Option<Idx>) {
+        let Some(index) = link else {
+            return;
+        };
+        let node = self.node(index);
+        let balance_factor = self.balance_factor(link);
+        if balance_factor > 1 {
+            let mut height_start = link;
+            if self.balance_factor(node.left) < 0 {
+                height_start = node.left;
+                self.rotate_left(node.left);
+            }
+            self.rotate_right(link);
+            self.update_ancestor_heights(height_start);
+        } else if balance_factor < -1 {
+            let mut height_start = link;
+            if self.balance_factor(node.right) > 0 {
+                height_start = node.right;
+                self.rotate_right(node.right);
+            }
+            self.rotate_left(link);
+            self.update_ancestor_heights(height_start);
+        }
+    }
+
+    fn rotate_right(&mut self, x_link: Option<Idx>) -> Option<Idx> {
+        let x_index = x_link?;
+        let (x_left, x_parent) = {
+            let x = self.node(x_index);
+            (x.left, x.parent)
+        };
+        let y_link = x_left;
+        let y_index = y_link?;
+        let t2_link = self.node(y_index).right;
+
+        #[cfg(feature = "stats")]
+        {
+            self.rotation_count += 1;
+        }
+
+        // fix parent -> y
+        if let Some(parent_index) = x_parent {
+            let parent_node_right = self.node(parent_index).right;
+            if parent_node_right == x_link {
+                self.with_occupied_mut(parent_index, |parent| parent.right = y_link);
+            } else {
+                self.with_occupied_mut(parent_index, |parent| parent.left = y_link);
+            }
+        } else {
+            self.root = y_link;
+        }
+
+        self.with_occupied_mut(y_index, |y| y.parent = x_parent);
+
+        // fix y -> x
+        self.with_occupied_mut(x_index, |x| x.parent = y_link);
+        self.with_occupied_mut(y_index, |y| y.right = x_link);
+
+        // fix x -> t2
+        self.with_occupied_mut(x_index, |x| x.left = t2_link);
+        if let Some(t2_index) = t2_link {
+            self.with_occupied_mut(t2_index, |t2| t2.parent = x_link);
+        }
+        y_link
+    }
+
+    fn rotate_left(&mut self, x_link: Option<Idx>) -> Option<Idx> {
+        let x_index = x_link?;
+        let (x_right, x_parent) = {
+            let x = self.node(x_index);
+            (x.right, x.parent)
+        };
+        let y_link = x_right;
+        let y_index = y_link?;
+        let t2_link = self.node(y_index).left;
+
+        #[cfg(feature = "stats")]
+        {
+            self.rotation_count += 1;
+        }
+
+        // fix parent -> y
+        if let Some(parent_index) = x_parent {
+            let parent_node_left = self.node(parent_index).left;
+            if parent_node_left == x_link {
+                self.with_occupied_mut(parent_index, |parent| parent.left = y_link);
+            } else {
+                self.with_occupied_mut(parent_index, |parent| parent.right = y_link);
+            }
+        } else {
+            self.root = y_link;
+        }
+
+        self.with_occupied_mut(y_index, |y| y.parent = x_parent);
+
+        // fix y -> x
+        self.with_occupied_mut(x_index, |x| x.parent = y_link);
+        self.with_occupied_mut(y_index, |y| y.left = x_link);
+
+        // fix x -> t2
+        self.with_occupied_mut(x_index, |x| x.right = t2_link);
+        if let Some(t2_index) = t2_link {
+            self.with_occupied_mut(t2_index, |t2| t2.parent = x_link);
+        }
+        y_link
+    }
+
+    fn update_ancestor_heights(&mut self, link: Option<Idx>) {
+        let mut cur = link;
+        while let Some(index) = cur {
+            self.update_height(index);
+            cur = self.node(index).parent;
+        }
+    }
+
+    fn update_height(&mut self, index: Idx) {
+        let node = self.node(index);
+        let left_height = self.link_height(node.left);
+        let right_height = self.link_height(node.right);
+
+        let node = self.node_mut(index);
+        node.height = 1 + left_height.max(right_height);
+    }
+
+    fn balance_factor(&self, link: Option<Idx>) -> i32 {
+        if let Some(index) = link {
+            let node = self.node(index);
+            let left_height = self.link_height(node.left);
+            let right_height = self.link_height(node.right);
+            left_height - right_height
+        } else {
+            0
+        }
+    }
+
+    fn link_height(&self, link: Option<Idx>) -> i32 {
+        match link {
+            Some(index) => self.node(index).height,
+            None => -1,
+        }
+    }
+
+    fn before(&self, index: Idx) -> Option<Idx> {
+        self.before_sub(index).or(self.before_above(index))
+    }
+
+    fn before_sub(&self, index: Idx) -> Option<Idx> {
+        let node = self.node(index);
+        let mut cur = node.left?;
+
+        while let Some(right) = self.node(cur).right {
+            cur = right;
+        }
+        Some(cur)
+    }
+
+    fn before_above(&self, index: Idx) -> Option<Idx> {
+        let node = self.node(index);
+        let mut cur_index = index;
+        let mut cur = node;
+        while let Some(parent_index) = cur.parent {
+            let parent = self.node(parent_index);
+            if parent.left == Some(cur_index) {
+                cur_index = parent_index;
+                cur = parent;
+            } else {
+                return cur.parent;
+            }
+        }
+        None
+    }
+
+    fn after(&self, index: Idx) -> Option<Idx> {
+        self.after_sub(index).or(self.after_above(index))
+    }
+
+    fn after_sub(&self, index: Idx) -> Option<Idx> {
+        let node = self.node(index);
+        let mut cur = node.right?;
+
+        while let Some(left) = self.node(cur).left {
+            cur = left;
+        }
+        Some(cur)
+    }
+
+    fn after_above(&self, index: Idx) -> Option<Idx> {
+        let node = self.node(index);
+        let mut cur_index = index;
+        let mut cur = node;
+        while let Some(parent_index) = cur.parent {
+            let parent = self.node(parent_index);
+            if parent.right == Some(cur_index) {
+                cur_index = parent_index;
+                cur = parent;
+            } else {
+                return cur.parent;
+            }
+        }
+        None
+    }
+
+    fn record_modification(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            self.mod_count += 1;
+        }
+    }
+
+    // every link is followed through node or node_mut, so a broken link panics here with its index
+    fn node(&self, index: Idx) -> &Node<T, Idx> {
+        match self.items.get(index.to_usize()) {
+            Some(Slot::Occupied { node }) => node,
+            Some(Slot::Free { .. }) => panic!("Corrupted arena: slot {index} is free"),
+            None => panic!(
+                "Corrupted arena: index {index} out of bounds for {} slots",
+                self.items.len()
+            ),
+        }
+    }
+
+    fn node_mut(&mut self, index: Idx) -> &mut Node<T, Idx> {
+        let len = self.items.len();
+        match self.items.get_mut(index.to_usize()) {
+            Some(Slot::Occupied { node }) => node,
+            Some(Slot::Free { .. }) => panic!("Corrupted arena: slot {index} is free"),
+            None => panic!("Corrupted arena: index {index} out of bounds for {len} slots"),
+        }
+    }
+
+    fn with_occupied_mut<F>(&mut self, index: Idx, f: F)
+    where
+        F: FnOnce(&mut Node<T, Idx>),
+    {
+        f(self.node_mut(index))
+    }
+
+    // next link of a free slot
+    fn next_free(&self, index: Idx) -> Option<Idx> {
+        match self.items.get(index.to_usize()) {
+            Some(Slot::Free { next_free }) => *next_free,
+            Some(Slot::Occupied { .. }) => {
+                panic!("Corrupted arena: free list points at occupied slot {index}")
+            }
+            None => panic!(
+                "Corrupted arena: free list index {index} out of bounds for {} slots",
+                self.items.len()
+            ),
+        }
+    }
+
+    // check_invariants with the order given by cmp, for TreeBy
+    fn check_invariants_by<F>(&self, cmp: F) -> Result<(), String>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        if let Some(root) = self.root
+            && self.node(root).parent.is_some()
+        {
+            return Err(format!("Root {} has a parent", root));
+        }
+        // a plain BST (see set_rebalance) only has to keep heights accurate
+        let (_, count) = self.check_subtree(self.root, self.rebalance)?;
+        if count != self.len {
+            return Err(format!("Tree has {} nodes but len is {}", count, self.len));
+        }
+
+        let mut free = 0;
+        let mut cur = self.head_free;
+        while let Some(index) = cur {
+            match self.items.get(index.to_usize()) {
+                Some(Slot::Free { next_free }) => cur = *next_free,
+                _ => return Err(format!("Free list points at non-free slot {}", index)),
+            }
+            free += 1;
+            if free > self.items.len() {
+                return Err("Free list contains a cycle".to_string());
+            }
+        }
+        // other trees' nodes fill the rest of a shared arena
+        if !self.shares_arena && free + self.len != self.items.len() {
+            return Err(format!(
+                "Arena has {} slots but {} are free and {} are used",
+                self.items.len(),
+                free,
+                self.len
+            ));
+        }
+
+        let mut prev: Option<&T> = None;
+        for value in self.iter() {
+            if prev.is_some_and(|prev| cmp(prev, value) != Ordering::Less) {
+                return Err("Values are not in ascending order".to_string());
+            }
+            prev = Some(value);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "checked")]
+    fn assert_invariants_by<F>(&self, operation: &str, cmp: F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        if let Err(message) = self.check_invariants_by(cmp) {
+            panic!("Tree invariants broken after {operation}: {message}");
+        }
+    }
+
+    // returns the recomputed height and the number of nodes in the subtree
+    fn check_subtree(&self, link: Option<Idx>, balanced: bool) -> Result<(i32, usize), String> {
+        let children = |index| {
+            let node = self.node(index);
+            (node.left, node.right)
+        };
+        tree::fold_subtrees(link, (-1, 0), children, |index, left, right| {
+            let node = self.node(index);
+            for child in [node.left, node.right].into_iter().flatten() {
+                if self.node(child).parent != Some(index) {
+                    return Err(format!("Child {} does not point back to {}", child, index));
+                }
+            }
+
+            let ((left_height, left_count), (right_height, right_count)) = (left, right);
+            let height = 1 + left_height.max(right_height);
+            if height != node.height {
+                return Err(format!(
+                    "Node {} stores height {} but has height {}",
+                    index, node.height, height
+                ));
+            }
+            if balanced && (left_height - right_height).abs() > 1 {
+                return Err(format!(
+                    "Node {} has balance factor {}",
+                    index,
+                    left_height - right_height
+                ));
+            }
+            Ok((height, left_count + right_count + 1))
+        })
+    }
+}
+
+impl<T: Ord, Idx: Index> FrozenTree<T, Idx> {
+    pub fn contains(&self, value: &T) -> bool {
+        self.tree.contains(value)
+    }
+
+    pub fn get(&self, value: &T) -> Option<&T> {
+        self.tree.get(value)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, Idx> {
+        self.tree.iter()
+    }
+
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T, Idx> {
+        self.tree.range(range)
+    }
+
+    pub fn min(&self) -> Option<&T> {
+        self.tree.first_value()
+    }
+
+    pub fn max(&self) -> Option<&T> {
+        self.tree.last_value()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.len == 0
+    }
+}
+
+impl<T: Ord> TreeOps<T> for MultiTree<T> {
+    // always adds another copy, so always returns true
+    fn insert(&mut self, value: T) -> bool {
+        match self
+            .tree
+            .find_closest_by(|counted| value.cmp(&counted.value))
+        {
+            Some((index, Ordering::Equal)) => self.tree.node_mut(index).value.count += 1,
+            _ => {
+                let _ = self.tree.insert(Counted { value, count: 1 });
+            }
+        }
+        self.len += 1;
+        true
+    }
+
+    // removes a single copy
+    fn remove(&mut self, value: &T) -> bool {
+        let Some((index, Ordering::Equal)) = self
+            .tree
+            .find_closest_by(|counted| value.cmp(&counted.value))
+        else {
+            return false;
+        };
+
+        let counted = &mut self.tree.node_mut(index).value;
+        if counted.count > 1 {
+            counted.count -= 1;
+        } else {
+            self.tree.remove_node(index);
+        }
+        self.len -= 1;
+        true
+    }
+
+    fn contains(&self, value: &T) -> bool {
+        self.count(value) > 0
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn height(&self) -> i32 {
+        self.tree.height()
+    }
+
+    fn is_balanced(&self) -> bool {
+        self.tree.is_balanced()
+    }
+
+    // each distinct value once, like contains
+    fn boxed_iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.iter().map(|(value, _)| value))
+    }
+}
+
+impl<T: Ord> MultiTree<T> {
+    pub fn new() -> Self {
+        MultiTree {
+            tree: Tree::new(),
+            len: 0,
+        }
+    }
+
+    // removes every copy and returns how many there were
+    pub fn remove_all(&mut self, value: &T) -> usize {
+        let Some((index, Ordering::Equal)) = self
+            .tree
+            .find_closest_by(|counted| value.cmp(&counted.value))
+        else {
+            return 0;
+        };
+        let count = self.tree.remove_node(index).count;
+        self.len -= count;
+        count
+    }
+
+    pub fn count(&self, value: &T) -> usize {
+        match self
+            .tree
+            .find_closest_by(|counted| value.cmp(&counted.value))
+        {
+            Some((index, Ordering::Equal)) => self.tree.node(index).value.count,
+            _ => 0,
+        }
+    }
+
+    // counts every copy, distinct_len counts each value once
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn distinct_len(&self) -> usize {
+        self.tree.len
+    }
+
+    // yields each distinct value once, with its count
+    pub fn iter(&self) -> impl Iterator<Item = (&T, usize)> {
+        self.tree
+            .iter()
+            .map(|counted| (&counted.value, counted.count))
+    }
+}
+
+impl<T: Ord> TreeArena<T> {
+    pub fn new() -> Self {
+        TreeArena {
+            shared: Tree {
+                shares_arena: true,
+                ..Tree::new()
+            },
+            roots: Vec::new(),
+        }
+    }
+
+    pub fn add_tree(&mut self) -> TreeId {
+        self.roots.push(ArenaRoot { root: None, len: 0 });
+        TreeId(self.roots.len() - 1)
+    }
+
+    pub fn insert(&mut self, id: TreeId, value: T) -> bool {
+        self.with_tree(id, |tree| tree.insert(value))
+    }
+
+    pub fn remove(&mut self, id: TreeId, value: &T) -> bool {
+        self.with_tree(id, |tree| tree.remove(value))
+    }
+
+    pub fn contains(&self, id: TreeId, value: &T) -> bool {
+        let root = self.roots[id.0].root;
+        matches!(
+            self.shared
+                .find_closest_from(root, |node_value| value.cmp(node_value)),
+            Some((_, Ordering::Equal))
+        )
+    }
+
+    pub fn len(&self, id: TreeId) -> usize {
+        self.roots[id.0].len
+    }
+
+    pub fn is_empty(&self, id: TreeId) -> bool {
+        self.roots[id.0].len == 0
+    }
+
+    pub fn iter(&self, id: TreeId) -> Iter<'_, T> {
+        let ArenaRoot { root, len } = self.roots[id.0];
+        let mut first = root;
+        while let Some(left) = first.and_then(|index| self.shared.node(index).left) {
+            first = Some(left);
+        }
+        Iter {
+            tree: &self.shared,
+            next: first,
+            remaining: len,
+            #[cfg(debug_assertions)]
+            mod_count: self.shared.mod_count,
+        }
+    }
+
+    // frees the tree's slots for reuse by the others, the id stays valid and empty
+    pub fn clear(&mut self, id: TreeId) {
+        let ArenaRoot { root, len } =
+            std::mem::replace(&mut self.roots[id.0], ArenaRoot { root: None, len: 0 });
+        let mut stack: Vec<usize> = root.into_iter().collect();
+        while let Some(index) = stack.pop() {
+            let node = self.shared.node(index);
+            stack.extend(node.left.into_iter().chain(node.right));
+            self.shared.remove_node_from_arena(index);
+        }
+        self.shared.len -= len;
+        self.shared.record_modification();
+    }
+
+    // slots in the shared arena, across all trees
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    // plugs the tree's root into the shared Tree for the duration of f; shared.len counts
+    // every tree's nodes, so only this tree's share is swapped in and out
+    fn with_tree<R>(&mut self, id: TreeId, f: impl FnOnce(&mut Tree<T>) -> R) -> R {
+        let ArenaRoot { root, len } = self.roots[id.0];
+        let others = self.shared.len - len;
+        self.shared.root = root;
+        self.shared.len = len;
+        let result = f(&mut self.shared);
+        self.roots[id.0] = ArenaRoot {
+            root: self.shared.root,
+            len: self.shared.len,
+        };
+        self.shared.root = None;
+        self.shared.len += others;
+        result
+    }
+}
+
+impl<T: Ord> Default for TreeArena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> Default for MultiTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, F: Fn(&T, &T) -> Ordering> TreeBy<T, F> {
+    pub fn with_cmp(cmp: F) -> Self {
+        TreeBy {
+            tree: Tree::default(),
+            cmp,
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> bool {
+        let closest = self.find_closest(&value);
+        let inserted = self.tree.link_value(value, closest).1;
+        #[cfg(feature = "checked")]
+        self.tree.assert_invariants_by("insert", &self.cmp);
+        inserted
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        matches!(self.find_closest(value), Some((_, Ordering::Equal)))
+    }
+
+    pub fn remove(&mut self, value: &T) -> bool {
+        let Some((index, Ordering::Equal)) = self.find_closest(value) else {
+            return false;
+        };
+        self.tree.unlink_node(index);
+        #[cfg(feature = "checked")]
+        self.tree.assert_invariants_by("remove", &self.cmp);
+        true
+    }
+
+    pub fn get(&self, value: &T) -> Option<&T> {
+        match self.find_closest(value) {
+            Some((index, Ordering::Equal)) => Some(&self.tree.node(index).value),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.len == 0
+    }
+
+    pub fn height(&self) -> i32 {
+        self.tree.height()
+    }
+
+    // in the comparator's order
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.tree.iter()
+    }
+
+    fn find_closest(&self, value: &T) -> Option<(usize, Ordering)> {
+        self.tree
+            .find_closest_by(|stored| (self.cmp)(value, stored))
+    }
+}
+
+impl<T: Ord> PartialEq for Counted<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T: Ord> Eq for Counted<T> {}
+
+impl<T: Ord> PartialOrd for Counted<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> Ord for Counted<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.cmp(&other.value)
+    }
+}
+
+impl<T: Ord + AbsDiff, Idx: Index> Tree<T, Idx> {
+    // closest stored value by difference, ties go to the smaller one
+    pub fn nearest(&self, value: &T) -> Option<&T> {
+        match self.floor_ceiling(value) {
+            (Some(floor), Some(ceiling)) => {
+                if ceiling.abs_diff(*value) < value.abs_diff(*floor) {
+                    Some(ceiling)
+                } else {
+                    Some(floor)
+                }
+            }
+            (floor, ceiling) => floor.or(ceiling),
+        }
+    }
+}
+
+// Box<T> is Ord whenever T is, including unsized T such as str or [u8]
+impl<T: Ord + ?Sized, Idx: Index> Tree<Box<T>, Idx> {
+    #[must_use]
+    pub fn insert_boxed(&mut self, value: impl Into<Box<T>>) -> bool {
+        self.insert(value.into())
+    }
+}
+
+impl<T: Ord + Display, Idx: Index> Tree<T, Idx> {
+    // renders the tree sideways: right subtree above, left subtree below, one level per indent
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::new();
+        self.write_ascii(self.root, 0, &mut out);
+        out
+    }
+
+    // Graphviz DOT with nodes named after their arena index and labeled with value and height
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph Tree {\n");
+        let mut stack: Vec<Idx> = self.root.into_iter().collect();
+        while let Some(index) = stack.pop() {
+            let node = self.node(index);
+            writeln!(
+                out,
+                "    n{} [label=\"{} (h={})\"];",
+                index, node.value, node.height
+            )
+            .unwrap();
+            for (child, side) in [(node.left, "L"), (node.right, "R")] {
+                if let Some(child_index) = child {
+                    writeln!(
+                        out,
+                        "    n{} -> n{} [label=\"{}\"];",
+                        index, child_index, side
+                    )
+                    .unwrap();
+                }
+            }
+            stack.extend(node.right);
+            stack.extend(node.left);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn write_ascii(&self, link: Option<Idx>, depth: usize, out: &mut String) {
+        let Some(index) = link else {
+            return;
+        };
+        let node = self.node(index);
+        self.write_ascii(node.right, depth + 1, out);
+        writeln!(
+            out,
+            "{}{} (h={}, bf={})",
+            "    ".repeat(depth),
+            node.value,
+            node.height,
+            self.balance_factor(link)
+        )
+        .unwrap();
+        self.write_ascii(node.left, depth + 1, out);
+    }
+}
+
+impl<T: Ord, Idx: Index> PartialEq for Tree<T, Idx> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Ord, Idx: Index> Eq for Tree<T, Idx> {}
+
+// lexicographic over the sorted values, a prefix orders before the longer tree
+impl<T: Ord, Idx: Index> PartialOrd for Tree<T, Idx> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord, Idx: Index> Ord for Tree<T, Idx> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+// hashes values in sorted order, so equal sets hash equally regardless of insertion order
+impl<T: Ord + Hash, Idx: Index> Hash for Tree<T, Idx> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for value in self.iter() {
+            value.hash(state);
+        }
+    }
+}
+
+impl<T: Ord + fmt::Debug, Idx: Index> fmt::Debug for Tree<T, Idx> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<T, Idx: Index> Default for Tree<T, Idx> {
+    fn default() -> Self {
+        Tree {
+            items: Vec::new(),
+            head_free: None,
+            root: None,
+            len: 0,
+            shrink_threshold: None,
+            duplicate_policy: DuplicatePolicy::Reject,
+            rebalance: true,
+            shares_arena: false,
+            next_generation: 0,
+            #[cfg(feature = "stats")]
+            rotation_count: 0,
+            #[cfg(debug_assertions)]
+            mod_count: 0,
+        }
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Entry<'a, T, Idx> {
+    pub fn or_insert(self) -> &'a mut T {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(),
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut T)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                entry.tree.debug_check_order(entry.index, "an entry");
+                Entry::Occupied(entry)
+            }
+            vacant => vacant,
+        }
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> OccupiedEntry<'a, T, Idx> {
+    pub fn get(&self) -> &T {
+        &self.tree.node(self.index).value
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.tree.node_mut(self.index).value
+    }
+
+    pub fn into_mut(self) -> &'a mut T {
+        &mut self.tree.node_mut(self.index).value
+    }
+
+    pub fn remove(self) -> T {
+        self.tree.remove_node(self.index)
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> VacantEntry<'a, T, Idx> {
+    pub fn insert(self) -> &'a mut T {
+        let (index, _) = self.tree.insert_at(self.value, self.closest);
+        &mut self.tree.node_mut(index).value
+    }
+}
+
+// copies the arena as it is, free slots included, so NodeRefs into the source also resolve
+// in the copy
+impl<T: Ord + Clone, Idx: Index> Clone for Tree<T, Idx> {
+    fn clone(&self) -> Self {
+        let mut tree = Tree::default();
+        tree.clone_from(self);
+        tree
+    }
+
+    // reuses self's arena allocation when it is already large enough
+    fn clone_from(&mut self, source: &Self) {
+        self.record_modification();
+        self.items.clone_from(&source.items);
+        self.head_free = source.head_free;
+        self.root = source.root;
+        self.len = source.len;
+        self.shrink_threshold = source.shrink_threshold;
+        self.rebalance = source.rebalance;
+        self.shares_arena = source.shares_arena;
+        self.duplicate_policy = source.duplicate_policy;
+        self.next_generation = source.next_generation;
+        #[cfg(feature = "stats")]
+        {
+            self.rotation_count = source.rotation_count;
+        }
+    }
+}
+
+// builds by insertion, stopping at the first value equal to one already inserted; only on
+// the default index type, like the constructors, so Tree::try_from needs no annotations
+impl<T: Ord> TryFrom<Vec<T>> for Tree<T> {
+    type Error = DuplicateError<T>;
+
+    fn try_from(values: Vec<T>) -> Result<Self, Self::Error> {
+        let mut tree = Tree::default();
+        for value in values {
+            let closest = tree.find_closest(&value);
+            if let Some((_, Ordering::Equal)) = closest {
+                return Err(DuplicateError(value));
+            }
+            tree.insert_at(value, closest);
+        }
+        Ok(tree)
+    }
+}
+
+impl<T: Ord, Idx: Index> Extend<T> for Tree<T, Idx> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            let _ = self.insert(value);
+        }
+    }
+}
+
+impl<'a, T: Ord + Copy + 'a, Idx: Index> Extend<&'a T> for Tree<T, Idx> {
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied());
+    }
+}
+
+impl<T: Ord, Idx: Index> IntoIterator for Tree<T, Idx> {
+    type Item = T;
+    type IntoIter = IntoIter<T, Idx>;
+
+    fn into_iter(self) -> IntoIter<T, Idx> {
+        IntoIter { tree: self }
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> IntoIterator for &'a Tree<T, Idx> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T, Idx>;
+
+    fn into_iter(self) -> Iter<'a, T, Idx> {
+        self.iter()
+    }
+}
+
+impl<T: Ord, Idx: Index> Iterator for IntoIter<T, Idx> {
+    type Item = T;
+    fn next(&mut self) -> Option<Self::Item> {
+        // no compaction, the tree is going away
+        self.tree.first().map(|ptr| self.tree.unlink_node(ptr))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.tree.len, Some(self.tree.len))
+    }
+}
+
+impl<T: Ord, Idx: Index> Iterator for Drain<'_, T, Idx> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.values.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
+}
+
+impl<'a, T, Idx: Index> Iterator for Iter<'a, T, Idx> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(debug_assertions)]
+        assert_eq!(
+            self.mod_count, self.tree.mod_count,
+            "Tree was modified while being iterated"
+        );
+        self.next.map(|index| {
+            let node = self.tree.node(index);
+            self.next = self.tree.after(index);
+            self.remaining -= 1;
+            &node.value
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Iterator for RevIter<'a, T, Idx> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(debug_assertions)]
+        assert_eq!(
+            self.mod_count, self.tree.mod_count,
+            "Tree was modified while being iterated"
+        );
+        self.next.map(|index| {
+            let node = self.tree.node(index);
+            self.next = self.tree.before(index);
+            self.remaining -= 1;
+            &node.value
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Iterator for Range<'a, T, Idx> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next.map(|index| {
+            let node = self.tree.node(index);
+            self.next = if self.next == self.last {
+                None
+            } else {
+                self.tree.after(index)
+            };
+            &node.value
+        })
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Iterator for RangeMut<'a, T, Idx> {
+    type Item = &'a mut T;
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.stack.pop()?;
+        // SAFETY: index has not been handed out yet; references already handed out
+        // point into other slots, which are never touched again
+        let node = unsafe { self.node_at(index) };
+        if Some(index) == self.last {
+            self.stack.clear();
+        } else {
+            let mut cur = node.right;
+            while let Some(child) = cur {
+                self.stack.push(child);
+                // SAFETY: children of a node not yet handed out come after it in order
+                cur = unsafe { self.node_at(child) }.left;
+            }
+        }
+        Some(&mut node.value)
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> RangeMut<'a, T, Idx> {
+    // SAFETY: no reference into the slot at index may be live, and the tree is borrowed
+    // mutably for 'a, so no slot is moved or freed meanwhile
+    unsafe fn node_at(&self, index: Idx) -> &'a mut Node<T, Idx> {
+        assert!(
+            index.to_usize() < self.slot_count,
+            "Corrupted arena: index {index} out of bounds for {} slots",
+            self.slot_count
+        );
+        match unsafe { &mut *self.slots.add(index.to_usize()) } {
+            Slot::Occupied { node } => node,
+            Slot::Free { .. } => panic!("Corrupted arena: slot {index} is free"),
+        }
+    }
+}
+
+// the raw pointer stands in for &'a mut Tree, which is Send and Sync under these bounds
+unsafe impl<T: Ord + Send, Idx: Index + Send> Send for RangeMut<'_, T, Idx> {}
+unsafe impl<T: Ord + Sync, Idx: Index + Sync> Sync for RangeMut<'_, T, Idx> {}
+
+impl<'a, T: Ord, Idx: Index> Iterator for PreorderIter<'a, T, Idx> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.stack.pop()?;
+        let node = self.tree.node(index);
+        self.stack.extend(node.right);
+        self.stack.extend(node.left);
+        self.remaining -= 1;
+        Some(&node.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Iterator for PostorderIter<'a, T, Idx> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<Self::Item> {
+        // a node is yielded the second time it is popped, once both subtrees are done
+        while let Some((index, expanded)) = self.stack.pop() {
+            let node = self.tree.node(index);
+            if expanded {
+                self.remaining -= 1;
+                return Some(&node.value);
+            }
+            self.stack.push((index, true));
+            self.stack.extend(node.right.map(|right| (right, false)));
+            self.stack.extend(node.left.map(|left| (left, false)));
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Iterator for LevelIter<'a, T, Idx> {
+    type Item = (usize, &'a T);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, depth) = self.queue.pop_front()?;
+        let node = self.tree.node(index);
+        self.queue.extend(node.left.map(|left| (left, depth + 1)));
+        self.queue
+            .extend(node.right.map(|right| (right, depth + 1)));
+        self.remaining -= 1;
+        Some((depth, &node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: Ord, Idx: Index> Iterator for MergeIter<'a, T, Idx> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((value, tree_index)) = self.heads.pop()?;
+        if let Some(next) = self.iters[tree_index].next() {
+            self.heads.push(Reverse((next, tree_index)));
+        }
+        self.remaining -= 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for IntoIter<T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for IntoIter<T, Idx> {}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for Drain<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for Drain<'_, T, Idx> {}
+
+impl<T, Idx: Index> ExactSizeIterator for Iter<'_, T, Idx> {}
+
+impl<T, Idx: Index> FusedIterator for Iter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for RevIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for RevIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for Range<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for RangeMut<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for PreorderIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for PreorderIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for PostorderIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for PostorderIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for LevelIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for LevelIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> ExactSizeIterator for MergeIter<'_, T, Idx> {}
+
+impl<T: Ord, Idx: Index> FusedIterator for MergeIter<'_, T, Idx> {}
+
+// Values present in several trees are yielded once per tree.
+pub fn merge_sorted<'a, T: Ord>(trees: &'a [&'a Tree<T>]) -> MergeIter<'a, T> {
+    let mut iters: Vec<Iter<'a, T>> = trees.iter().map(|tree| tree.iter()).collect();
+    let remaining = trees.iter().map(|tree| tree.len).sum();
+    let heads = iters
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(tree_index, iter)| iter.next().map(|value| Reverse((value, tree_index))))
+        .collect();
+    MergeIter {
+        iters,
+        heads,
+        remaining,
+    }
+}
+
+impl<T, Idx: Index> Node<T, Idx> {
+    pub fn new(value: T, generation: u64) -> Self {
+        Node {
+            value,
+            height: 0,
+            generation,
+            parent: None,
+            left: None,
+            right: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // orders by key only, so equal values can carry different payloads
+    #[derive(Debug)]
+    struct Keyed(i32, &'static str);
+
+    impl PartialEq for Keyed {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Keyed {}
+
+    impl PartialOrd for Keyed {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Keyed {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn empty_after_creation() {
+        let tree = Tree::<i32>::new();
+        assert_eq!(tree.len(), 0);
+    }
+
+    #[test]
+    fn insert_and_contains() {
+        let mut tree = Tree::<i32>::new();
+        for i in 0..10 {
+            assert_eq!(tree.len(), i as usize);
+            assert!(tree.insert(i));
+            assert!(tree.contains(&i));
+        }
+        for i in 0..10 {
+            assert!(tree.contains(&i));
+        }
+        assert!(!tree.contains(&100));
+    }
+
+    #[test]
+    fn insert_large_logarithmic_height() {
+        let mut tree = Tree::<i32>::new();
+        let size = 1000;
+        for i in 0..size {
+            assert_eq!(tree.len(), i as usize);
+            assert!(tree.insert(i));
+            assert!(tree.contains(&i));
+        }
+        for i in 0..size {
+            assert!(tree.contains(&i));
+        }
+
+        assert_eq!(tree.height(), size.ilog2() as i32);
+    }
+
+    #[test]
+    fn remove_and_contains() {
+        let mut tree = Tree::<i32>::new();
+        for i in 0..10 {
+            assert!(tree.insert(i));
+        }
+        for i in 0..10 {
+            assert!(tree.contains(&i));
+        }
+        for i in 0..10 {
+            for j in 0..i {
+                assert_eq!(tree.contains(&j), false);
+            }
+            for j in i..10 {
+                assert_eq!(tree.contains(&j), true);
+            }
+
+            let removed = tree.remove(&i);
+            assert_eq!(removed, true);
+
+            for j in 0..i + 1 {
+                assert_eq!(tree.contains(&j), false);
+            }
+            for j in i + 1..10 {
+                assert_eq!(tree.contains(&j), true);
+            }
+        }
+    }
+
+    #[test]
+    fn first_after_asc_insert() {
+        let mut tree = Tree::new();
+        for i in 0..10 {
+            assert!(tree.insert(i));
+        }
+        assert_eq!(tree.first().map(|index| tree.node(index).value), Some(0));
+    }
+
+    #[test]
+    fn first_after_desc_insert() {
+        let mut tree = Tree::new();
+        for i in (0..10).rev() {
+            assert!(tree.insert(i));
+        }
+        assert_eq!(tree.first().map(|index| tree.node(index).value), Some(0));
+    }
+
+    #[test]
+    fn into_iter_asc() {
+        let mut tree = Tree::new();
+        for i in 0..10 {
+            assert!(tree.insert(i));
+        }
+
+        let mut iter = tree.into_iter();
+        for i in 0..10 {
+            assert_eq!(iter.next(), Some(i));
+        }
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn into_iter_desc() {
+        let mut tree = Tree::new();
+        for i in (0..10).rev() {
+            assert!(tree.insert(i));
+        }
+
+        let mut iter = tree.into_iter();
+        for i in 0..10 {
+            assert_eq!(iter.next(), Some(i));
+        }
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn iter_asc() {
+        let mut tree = Tree::new();
+        for i in 0..10 {
+            assert!(tree.insert(i));
+        }
+
+        let mut iter = tree.iter();
+        for i in 0..10 {
+            assert_eq!(iter.next(), Some(&i));
+        }
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn iter_desc() {
+        let mut tree = Tree::new();
+        for i in (0..10).rev() {
+            assert!(tree.insert(i));
+        }
+
+        let mut iter = tree.iter();
+        for i in 0..10 {
+            assert_eq!(iter.next(), Some(&i));
+        }
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn merge_sorted_overlapping_trees() {
+        let ranges = [0..20, 10..30, 5..15];
+        let trees: Vec<Tree<i32>> = ranges
+            .iter()
+            .map(|range| {
+                let mut tree = Tree::new();
+                for i in range.clone().rev() {
+                    assert!(tree.insert(i));
+                }
+                tree
+            })
+            .collect();
+        let refs: Vec<&Tree<i32>> = trees.iter().collect();
+
+        let mut expected: Vec<i32> = ranges.iter().flat_map(|range| range.clone()).collect();
+        expected.sort();
+
+        let merged: Vec<i32> = merge_sorted(&refs).copied().collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn merge_sorted_empty() {
+        let empty = Tree::<i32>::new();
+        assert_eq!(merge_sorted::<i32>(&[]).next(), None);
+        assert_eq!(merge_sorted(&[&empty, &empty]).next(), None);
+    }
+
+    #[test]
+    fn first_last_nth_match_sorted_vec() {
+        let values = [50, 20, 80, 10, 30, 70, 90, 60, 40];
+        let mut tree = Tree::new();
+        assert_eq!(tree.first_value(), None);
+        assert_eq!(tree.last_value(), None);
+        assert_eq!(tree.nth(0), None);
+
+        for &value in values.iter() {
+            assert!(tree.insert(value));
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort();
+
+        assert_eq!(tree.first_value(), sorted.first());
+        assert_eq!(tree.last_value(), sorted.last());
+        for k in 0..sorted.len() + 2 {
+            assert_eq!(tree.nth(k), sorted.get(k));
+        }
+    }
+
+    #[test]
+    fn try_insert_new_and_duplicate() {
+        let mut tree = Tree::new();
+        for i in 0..10 {
+            assert_eq!(tree.try_insert(i), Ok(true));
+        }
+        for i in 0..10 {
+            assert_eq!(tree.try_insert(i), Ok(false));
+        }
+        assert_eq!(tree.len(), 10);
+
+        assert!(tree.remove(&5));
+        assert_eq!(tree.try_insert(5), Ok(true));
+        assert!(tree.contains(&5));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn rotation_count_sorted_vs_balanced_insert() {
+        let mut sorted = Tree::new();
+        for i in [1, 2, 3] {
+            assert!(sorted.insert(i));
+        }
+        assert_eq!(sorted.rotation_count(), 1);
+
+        let mut balanced = Tree::new();
+        for i in [2, 1, 3] {
+            assert!(balanced.insert(i));
+        }
+        assert_eq!(balanced.rotation_count(), 0);
+    }
+
+    #[test]
+    fn dfs_orders_on_balanced_tree() {
+        let mut tree = Tree::new();
+        for i in [4, 2, 6, 1, 3, 5, 7] {
+            assert!(tree.insert(i));
+        }
+
+        let inorder: Vec<i32> = tree.iter().copied().collect();
+        let preorder: Vec<i32> = tree.iter_preorder().copied().collect();
+        let postorder: Vec<i32> = tree.iter_postorder().copied().collect();
+        assert_eq!(inorder, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(preorder, vec![4, 2, 1, 3, 6, 5, 7]);
+        assert_eq!(postorder, vec![1, 3, 2, 5, 7, 6, 4]);
+    }
+
+    #[test]
+    fn dfs_orders_on_empty_tree() {
+        let tree = Tree::<i32>::new();
+        assert_eq!(tree.iter_preorder().next(), None);
+        assert_eq!(tree.iter_postorder().next(), None);
+    }
+
+    #[test]
+    fn iter_levels_on_balanced_tree() {
+        let mut tree = Tree::new();
+        for i in 1..=7 {
+            assert!(tree.insert(i));
+        }
+
+        let levels: Vec<(usize, i32)> = tree
+            .iter_levels()
+            .map(|(depth, &value)| (depth, value))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![(0, 4), (1, 2), (1, 6), (2, 1), (2, 3), (2, 5), (2, 7)]
+        );
+        assert_eq!(Tree::<i32>::new().iter_levels().next(), None);
+    }
+
+    #[test]
+    fn to_ascii_small_tree() {
+        let mut tree = Tree::new();
+        for i in [2, 1, 3, 4] {
+            assert!(tree.insert(i));
+        }
+
+        let expected =
+            "        4 (h=0, bf=0)\n    3 (h=1, bf=-1)\n2 (h=2, bf=-1)\n    1 (h=0, bf=0)\n";
+        assert_eq!(tree.to_ascii(), expected);
+        assert_eq!(Tree::<i32>::new().to_ascii(), "");
+    }
+
+    #[test]
+    fn random_ops_match_btreeset() {
+        use rand::{Rng, SeedableRng};
+        use std::collections::BTreeSet;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree = Tree::new();
+        let mut reference = BTreeSet::new();
+        for _ in 0..5000 {
+            let value: i32 = rng.random_range(0..200);
+            match rng.random_range(0..3) {
+                0 => assert_eq!(tree.insert(value), reference.insert(value)),
+                1 => assert_eq!(tree.remove(&value), reference.remove(&value)),
+                _ => assert_eq!(tree.contains(&value), reference.contains(&value)),
+            }
+            assert_eq!(tree.len(), reference.len());
+        }
+        assert!(tree.iter().eq(reference.iter()));
+    }
+
+    #[test]
+    fn size_hint_of_traversal_orders() {
+        // iter and into_iter are covered for every backend in tree.rs
+        let mut tree = Tree::new();
+        for i in 0..10 {
+            assert!(tree.insert(i));
+        }
+
+        assert_eq!(tree.iter_preorder().size_hint(), (10, Some(10)));
+        assert_eq!(tree.iter_postorder().size_hint(), (10, Some(10)));
+        assert_eq!(tree.iter_levels().size_hint(), (10, Some(10)));
+        assert_eq!(merge_sorted(&[&tree, &tree]).size_hint(), (20, Some(20)));
+    }
+
+    #[test]
+    fn exact_size_and_fused() {
+        let mut tree = Tree::new();
+        for i in 0..5 {
+            assert!(tree.insert(i));
+        }
+
+        let mut iter = tree.iter();
+        assert_eq!(iter.len(), 5);
+        iter.next();
+        assert_eq!(iter.len(), 4);
+        for _ in 0..4 {
+            iter.next();
+        }
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+
+        let mut levels = tree.iter_levels();
+        levels.next();
+        assert_eq!(levels.len(), 4);
+
+        let mut into_iter = tree.into_iter();
+        assert_eq!(into_iter.len(), 5);
+        assert_eq!(into_iter.by_ref().count(), 5);
+        assert_eq!(into_iter.next(), None);
+        assert_eq!(into_iter.next(), None);
+    }
+
+    #[test]
+    fn append_overlapping_trees() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for i in 0..60 {
+            assert!(a.insert(i));
+        }
+        for i in 40..100 {
+            assert!(b.insert(i));
+        }
+        assert!(a.remove(&10));
+        assert!(b.remove(&90));
+
+        a.append(&mut b);
+        let expected: Vec<i32> = (0..100).filter(|&i| i != 10 && i != 90).collect();
+        assert!(a.iter().copied().eq(expected.iter().copied()));
+        assert_eq!(a.len(), expected.len());
+        assert_eq!(a.height(), (expected.len() as u32).ilog2() as i32);
+        assert_eq!(b.len(), 0);
+        assert_eq!(b.iter().next(), None);
+
+        // both trees stay usable afterwards
+        assert!(a.insert(10));
+        assert!(a.remove(&50));
+        assert!(b.insert(1));
+        assert!(b.contains(&1));
+    }
+
+    #[test]
+    fn append_keeps_own_duplicate() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        assert!(a.insert(Keyed(1, "self")));
+        assert!(b.insert(Keyed(1, "other")));
+        assert!(b.insert(Keyed(2, "other")));
+        a.append(&mut b);
+        let labels: Vec<&str> = a.iter().map(|keyed| keyed.1).collect();
+        assert_eq!(labels, vec!["self", "other"]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Tree was modified while being iterated")]
+    fn iter_detects_modification() {
+        let mut tree = Tree::new();
+        for i in 0..10 {
+            assert!(tree.insert(i));
+        }
+
+        // the borrow checker rules out a real change during iteration, so give the iterator
+        // the count it would have taken before an insert
+        let before = tree.mod_count;
+        assert!(tree.insert(100));
+        let mut iter = tree.iter();
+        iter.mod_count = before;
+        iter.next();
+    }
+
+    #[test]
+    fn equal_trees_hash_equally() {
+        use std::collections::hash_map::DefaultHasher;
+
+        fn hash_of(tree: &Tree<i32>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            tree.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let mut asc = Tree::new();
+        let mut desc = Tree::new();
+        let mut other = Tree::new();
+        for i in 0..20 {
+            assert!(asc.insert(i));
+            assert!(desc.insert(19 - i));
+            assert!(other.insert(i + 1));
+        }
+
+        assert!(asc == desc);
+        assert_eq!(hash_of(&asc), hash_of(&desc));
+        assert!(asc != other);
+        assert_ne!(hash_of(&asc), hash_of(&other));
+    }
+
+    #[test]
+    fn remove_shuffled_order() {
+        use rand::SeedableRng;
+        use rand::seq::SliceRandom;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree = Tree::new();
+        for i in 0..1000 {
+            assert!(tree.insert(i));
+        }
+        let mut order: Vec<i32> = (0..1000).collect();
+        order.shuffle(&mut rng);
+
+        for (removed_count, value) in order.iter().enumerate() {
+            assert!(tree.contains(value));
+            assert!(tree.remove(value));
+            assert!(!tree.contains(value));
+            assert!(!tree.remove(value));
+            assert_eq!(tree.len(), 1000 - removed_count - 1);
+            assert_eq!(tree.check_invariants(), Ok(()));
+        }
+        for i in 0..1000 {
+            assert!(!tree.contains(&i));
+        }
+    }
+
+    #[test]
+    fn free_slots_after_removals() {
+        let mut tree = Tree::new();
+        assert_eq!(tree.free_slots(), 0);
+        for i in 0..100 {
+            assert!(tree.insert(i));
+        }
+        assert!(tree.capacity() >= 100);
+        for i in (0..100).step_by(2) {
+            assert!(tree.remove(&i));
+        }
+        assert_eq!(tree.free_slots(), 50);
+        assert_eq!(tree.len(), 50);
+
+        for i in 100..110 {
+            assert!(tree.insert(i));
+        }
+        assert_eq!(tree.free_slots(), 40);
+    }
+
+    #[test]
+    fn lexicographic_ordering() {
+        let tree_of = |values: &[i32]| {
+            let mut tree = Tree::new();
+            for &value in values {
+                assert!(tree.insert(value));
+            }
+            tree
+        };
+        let a = tree_of(&[1, 2]);
+        let b = tree_of(&[2, 1, 3]);
+        let c = tree_of(&[3, 1]);
+
+        assert!(Tree::<i32>::new() < a);
+        assert!(a < b);
+        assert!(b < c);
+        assert!(a < c);
+        assert_eq!(a.cmp(&tree_of(&[2, 1])), Ordering::Equal);
+
+        let mut sorted = [c, a, b];
+        sorted.sort();
+        let lens: Vec<usize> = sorted.iter().map(|tree| tree.len()).collect();
+        assert_eq!(lens, vec![2, 3, 2]);
+    }
+
+    #[test]
+    fn range_bounds() {
+        let mut tree = Tree::new();
+        for i in (0..20).map(|i| i * 2) {
+            assert!(tree.insert(i));
+        }
+        let collect = |range: Range<'_, i32>| range.copied().collect::<Vec<i32>>();
+
+        assert_eq!(collect(tree.range(4..10)), vec![4, 6, 8]);
+        assert_eq!(collect(tree.range(3..=10)), vec![4, 6, 8, 10]);
+        assert_eq!(collect(tree.range(..3)), vec![0, 2]);
+        assert_eq!(collect(tree.range(35..)), vec![36, 38]);
+        assert_eq!(collect(tree.range(..)).len(), 20);
+        assert_eq!(
+            collect(tree.range((Bound::Excluded(4), Bound::Excluded(8)))),
+            vec![6]
+        );
+        assert_eq!(collect(tree.range(5..6)), Vec::<i32>::new());
+        assert_eq!(collect(tree.range(100..)), Vec::<i32>::new());
+        assert_eq!(collect(Tree::new().range(0..10)), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn range_mut_bumps_only_the_range() {
+        let mut tree = Tree::new();
+        for i in 0..20 {
+            assert!(tree.insert(i * 10));
+        }
+        for value in tree.range_mut(50..=100) {
+            *value += 1;
+        }
+        let expected: Vec<i32> = (0..20)
+            .map(|i| i * 10)
+            .map(|v| if (50..=100).contains(&v) { v + 1 } else { v })
+            .collect();
+        assert_eq!(tree.iter().copied().collect::<Vec<_>>(), expected);
+        assert!(tree.contains(&51));
+        assert!(!tree.contains(&50));
+        assert_eq!(tree.range_mut(1000..).count(), 0);
+        assert!(tree.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn frozen_tree_shared_across_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let mut tree = Tree::new();
+        for i in 0..1000 {
+            assert!(tree.insert(i));
+        }
+        let frozen = Arc::new(tree.into_frozen());
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let frozen = Arc::clone(&frozen);
+                thread::spawn(move || {
+                    for i in (t..1000).step_by(4) {
+                        assert!(frozen.contains(&i));
+                        assert_eq!(frozen.get(&i), Some(&i));
+                    }
+                    assert!(!frozen.contains(&1000));
+                    frozen.range(100..110).count()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 10);
+        }
+
+        assert_eq!(frozen.min(), Some(&0));
+        assert_eq!(frozen.max(), Some(&999));
+        assert_eq!(frozen.len(), 1000);
+        assert!(frozen.iter().copied().eq(0..1000));
+    }
+
+    #[test]
+    fn multi_tree_counts_duplicates() {
+        let mut tree = MultiTree::new();
+        for _ in 0..3 {
+            assert!(tree.insert(7));
+        }
+        assert!(tree.insert(3));
+        assert!(tree.insert(9));
+
+        assert_eq!(tree.count(&7), 3);
+        assert_eq!(tree.count(&3), 1);
+        assert_eq!(tree.count(&5), 0);
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.distinct_len(), 3);
+        let counts: Vec<(i32, usize)> = tree.iter().map(|(&value, count)| (value, count)).collect();
+        assert_eq!(counts, vec![(3, 1), (7, 3), (9, 1)]);
+
+        assert!(tree.remove(&7));
+        assert!(tree.remove(&7));
+        assert_eq!(tree.count(&7), 1);
+        assert!(tree.contains(&7));
+        assert_eq!(tree.distinct_len(), 3);
+
+        assert!(tree.remove(&7));
+        assert!(!tree.contains(&7));
+        assert!(!tree.remove(&7));
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.distinct_len(), 2);
+        assert_eq!(tree.tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn bounds_empty_single_and_populated() {
+        let mut tree = Tree::new();
+        assert_eq!(tree.bounds(), None);
+        assert!(tree.insert(5));
+        assert_eq!(tree.bounds(), Some((&5, &5)));
+        for i in [9, 1, 7, 3] {
+            assert!(tree.insert(i));
+        }
+        assert_eq!(tree.bounds(), Some((&1, &9)));
+    }
+
+    #[test]
+    fn insert_after_hint_matches_insert() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut hinted = Tree::new();
+        let mut plain = Tree::new();
+        let mut hint = hinted.insert_ref(0);
+        assert!(plain.insert(0));
+        let mut last = 0;
+        for _ in 0..2000 {
+            // locally clustered values around the previous one
+            let value: i32 = last + rng.random_range(-20..20);
+            let (node, new) = hinted.insert_after_hint(value, hint);
+            assert_eq!(new, plain.insert(value));
+            assert_eq!(hinted.value_at(node), Some(&value));
+            (hint, last) = (node, value);
+            assert_eq!(hinted.check_invariants(), Ok(()));
+        }
+        assert!(hinted == plain);
+
+        // a stale hint falls back to searching from the root
+        assert_eq!(hinted.remove_ref(hint), Some(last));
+        let (node, new) = hinted.insert_after_hint(1_000_000, hint);
+        assert!(new);
+        assert_eq!(hinted.value_at(node), Some(&1_000_000));
+    }
+
+    #[test]
+    fn to_dot_three_nodes() {
+        let mut tree = Tree::new();
+        for i in [1, 2, 3] {
+            assert!(tree.insert(i));
+        }
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph Tree {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("    n1 [label=\"2 (h=1)\"];\n"));
+        assert!(dot.contains("    n0 [label=\"1 (h=0)\"];\n"));
+        assert!(dot.contains("    n2 [label=\"3 (h=0)\"];\n"));
+        assert!(dot.contains("    n1 -> n0 [label=\"L\"];\n"));
+        assert!(dot.contains("    n1 -> n2 [label=\"R\"];\n"));
+        assert_eq!(dot.matches("->").count(), 2);
+    }
+
+    #[test]
+    fn to_sorted_vec_ascending() {
+        let mut tree = Tree::new();
+        for i in [5, 3, 8, 1, 4] {
+            assert!(tree.insert(i));
+        }
+        let values = tree.to_sorted_vec();
+        assert_eq!(values, vec![&1, &3, &4, &5, &8]);
+        assert_eq!(values.capacity(), 5);
+    }
+
+    #[test]
+    fn take_min_max_shrink_arena() {
+        let mut tree = Tree::new();
+        tree.set_shrink_policy(Some(0.5));
+        for i in 0..1000 {
+            assert!(tree.insert(i));
+        }
+        let peak = tree.capacity();
+
+        let mut shrunk_at = None;
+        for i in 0..500 {
+            assert_eq!(tree.take_min(), Some(i));
+            assert_eq!(tree.take_max(), Some(999 - i));
+            if shrunk_at.is_none() && tree.capacity() < peak {
+                shrunk_at = Some(tree.len());
+            }
+            assert_eq!(tree.check_invariants(), Ok(()));
+        }
+        assert!(shrunk_at.is_some_and(|len| len > 0));
+        assert_eq!(tree.take_min(), None);
+        assert_eq!(tree.take_max(), None);
+
+        // compaction is opt-in
+        let mut kept = Tree::new();
+        for i in 0..1000 {
+            assert!(kept.insert(i));
+        }
+        let peak = kept.capacity();
+        while kept.take_min().is_some() {}
+        assert_eq!(kept.capacity(), peak);
+    }
+
+    #[test]
+    fn from_unsorted_is_sorted_and_balanced() {
+        let values: Vec<i32> = (0..1000).map(|i| (i * 7919) % 1000).collect();
+        let tree = Tree::from_unsorted(values);
+        assert_eq!(tree.len(), 1000);
+        assert!(tree.iter().copied().eq(0..1000));
+        // 1000 nodes fit in ten levels, and height counts edges from a leaf
+        assert_eq!(tree.height(), 9);
+        assert!(tree.check_invariants().is_ok());
+
+        let tree = Tree::from_unsorted(vec![3, 1, 3, 2, 1]);
+        assert!(tree.iter().copied().eq(1..=3));
+        assert!(Tree::<i32>::from_unsorted(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn set_relations() {
+        let build = |values: &[i32]| {
+            let mut tree = Tree::new();
+            for &v in values {
+                assert!(tree.insert(v));
+            }
+            tree
+        };
+        let small = build(&[2, 4, 6]);
+        let big = build(&[1, 2, 3, 4, 5, 6]);
+        let odd = build(&[1, 3, 5]);
+
+        assert!(small.is_subset(&big));
+        assert!(!big.is_subset(&small));
+        assert!(big.is_superset(&small));
+        assert!(small.is_disjoint(&odd));
+        assert!(!big.is_disjoint(&odd));
+        assert!(Tree::new().is_subset(&small));
+        assert!(Tree::<i32>::new().is_disjoint(&Tree::new()));
+    }
+
+    #[test]
+    fn nearest_picks_closer_neighbor() {
+        let mut tree = Tree::new();
+        for i in [10, 20, 35, 50] {
+            assert!(tree.insert(i));
+        }
+        assert_eq!(tree.floor_ceiling(&25), (Some(&20), Some(&35)));
+        assert_eq!(tree.floor_ceiling(&35), (Some(&35), Some(&35)));
+        assert_eq!(tree.floor_ceiling(&5), (None, Some(&10)));
+        assert_eq!(tree.floor_ceiling(&60), (Some(&50), None));
+
+        assert_eq!(tree.nearest(&24), Some(&20));
+        assert_eq!(tree.nearest(&31), Some(&35));
+        assert_eq!(tree.nearest(&15), Some(&10));
+        assert_eq!(tree.nearest(&35), Some(&35));
+        assert_eq!(tree.nearest(&-100), Some(&10));
+        assert_eq!(tree.nearest(&100), Some(&50));
+        assert_eq!(Tree::<i32>::new().nearest(&1), None);
+
+        // the distances don't fit in i32
+        let extremes: Tree<i32> = Tree::from_sorted(vec![i32::MIN, i32::MAX]);
+        assert_eq!(extremes.nearest(&0), Some(&i32::MAX));
+        assert_eq!(extremes.nearest(&1), Some(&i32::MAX));
+        assert_eq!(extremes.nearest(&-1), Some(&i32::MIN));
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted arena: free list points at occupied slot 0")]
+    fn corrupted_free_list_panics_with_index() {
+        let mut tree = Tree::new();
+        for i in 0..3 {
+            assert!(tree.insert(i));
+        }
+        tree.set_head_free(Some(0));
+        assert!(tree.insert(10));
+    }
+
+    #[test]
+    fn parents_stay_consistent_under_random_ops() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(851);
+        let mut tree = Tree::new();
+        assert!(tree.verify_parents());
+        for _ in 0..2000 {
+            let value = rng.random_range(0..200);
+            if rng.random_bool(0.6) {
+                let _ = tree.insert(value);
+            } else {
+                let _ = tree.remove(&value);
+            }
+            assert!(tree.verify_parents());
+        }
+
+        let root = tree.root.expect("Tree should not be empty");
+        let child = tree.node(root).left.expect("Root should have a left child");
+        tree.node_mut(child).parent = None;
+        assert!(!tree.verify_parents());
+    }
+
+    #[test]
+    fn extend_from_slices() {
+        let mut tree = Tree::new();
+        tree.extend_from_slice(&[5, 1, 3]);
+        tree.extend(&[3, 7]);
+        tree.extend([9, 1].iter().copied());
+        assert_eq!(tree.len(), 5);
+        for value in [1, 3, 5, 7, 9] {
+            assert!(tree.contains(&value));
+        }
+        assert!(!tree.contains(&2));
+
+        let mut words = Tree::new();
+        words.extend_from_slice(&["b".to_string(), "a".to_string()]);
+        assert_eq!(words.first_value().map(String::as_str), Some("a"));
+    }
+
+    #[test]
+    fn drain_empties_and_keeps_capacity() {
+        let mut tree = Tree::new();
+        for i in [4, 2, 6, 1, 3, 5, 7] {
+            assert!(tree.insert(i));
+        }
+        let capacity = tree.capacity();
+        assert_eq!(tree.drain().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(tree.len(), 0);
+        assert_eq!(tree.capacity(), capacity);
+
+        tree.extend([10, 20, 30]);
+        let mut drain = tree.drain();
+        assert_eq!(drain.next(), Some(10));
+        drop(drain);
+        assert!(tree.is_empty());
+        assert!(!tree.contains(&20));
+        assert_eq!(tree.capacity(), capacity);
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn u32_indexed_tree() {
+        assert!(size_of::<Slot<i32, u32>>() < size_of::<Slot<i32, usize>>());
+
+        let mut tree: Tree<i32, u32> = Tree::default();
+        for i in (0..200).rev() {
+            assert!(tree.insert(i));
+        }
+        for i in (0..200).step_by(3) {
+            assert!(tree.remove(&i));
+        }
+        // freed slots are reused through the u32 free list
+        for i in 200..250 {
+            assert!(tree.insert(i));
+        }
+        assert_eq!(tree.len(), 200 - 67 + 50);
+        assert!(
+            tree.iter()
+                .copied()
+                .eq((0..250).filter(|i| *i >= 200 || i % 3 != 0))
+        );
+        assert_eq!(tree.check_invariants(), Ok(()));
+        assert!(tree.verify_parents());
+    }
+
+    #[test]
+    fn rebuild_reaches_minimal_height() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(862);
+        let mut tree = Tree::new();
+        for _ in 0..5000 {
+            let value: i32 = rng.random_range(0..2000);
+            if rng.random_bool(0.7) {
+                let _ = tree.insert(value);
+            } else {
+                let _ = tree.remove(&value);
+            }
+        }
+        let before: Vec<i32> = tree.iter().copied().collect();
+        let capacity = tree.capacity();
+
+        tree.rebuild();
+        assert_eq!(tree.height(), tree.len().ilog2() as i32);
+        assert!(tree.iter().copied().eq(before));
+        assert_eq!(tree.capacity(), capacity);
+        assert_eq!(tree.free_slots(), 0);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let mut empty = Tree::<i32>::new();
+        empty.rebuild();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn any_in_range_short_circuits() {
+        let mut tree = Tree::new();
+        for i in [10, 20, 30] {
+            assert!(tree.insert(i));
+        }
+        assert!(tree.any_in_range(15..=25));
+        assert!(tree.any_in_range(20..21));
+        assert!(tree.any_in_range(..11));
+        assert!(tree.any_in_range(30..));
+        assert!(tree.any_in_range(..));
+        assert!(!tree.any_in_range(11..20));
+        assert!(!tree.any_in_range(21..=29));
+        assert!(!tree.any_in_range(31..));
+        assert!(!tree.any_in_range(..10));
+        assert!(!tree.any_in_range((Bound::Excluded(10), Bound::Excluded(20))));
+        assert!(!Tree::<i32>::new().any_in_range(..));
+    }
+
+    #[test]
+    fn node_refs_detect_staleness() {
+        let mut tree = Tree::new();
+        let refs: Vec<NodeRef> = (0..20).map(|i| tree.insert_ref(i * 10)).collect();
+        // rotations during later inserts don't move nodes between slots
+        for (i, &node_ref) in refs.iter().enumerate() {
+            assert_eq!(tree.value_at(node_ref), Some(&(i as i32 * 10)));
+        }
+        assert_eq!(tree.insert_ref(50), refs[5]);
+
+        assert_eq!(tree.remove_ref(refs[3]), Some(30));
+        assert_eq!(tree.value_at(refs[3]), None);
+        assert_eq!(tree.remove_ref(refs[3]), None);
+        assert!(!tree.contains(&30));
+
+        // the freed slot is reused by the next insert, the old ref must not see the new value
+        let reused = tree.insert_ref(35);
+        assert_eq!(tree.value_at(reused), Some(&35));
+        assert_eq!(tree.value_at(refs[3]), None);
+
+        tree.rebuild();
+        assert_eq!(tree.value_at(refs[0]), None);
+        assert_eq!(tree.value_at(reused), None);
+        assert_eq!(tree.len(), 20);
+    }
+
+    #[test]
+    fn into_sorted_vec_dedups_and_sorts() {
+        let input = vec![9, 3, 7, 3, 1, 9, 5, 1];
+        let mut tree = Tree::new();
+        tree.extend_from_slice(&input);
+
+        let mut expected = input;
+        expected.sort();
+        expected.dedup();
+        let values = tree.into_sorted_vec();
+        assert_eq!(values, expected);
+        assert_eq!(values.capacity(), values.len());
+        assert!(Tree::<i32>::new().into_sorted_vec().is_empty());
+    }
+
+    #[test]
+    fn interleaved_workload_reuses_free_slots() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(870);
+        let mut tree = Tree::new();
+        let mut peak_len = 0;
+        for _ in 0..20_000 {
+            let value: i32 = rng.random_range(0..500);
+            if rng.random_bool(0.5) {
+                let had_free = tree.free_slots() > 0;
+                let slots = tree.items.len();
+                if tree.insert(value) && had_free {
+                    assert_eq!(tree.items.len(), slots, "grew while a free slot existed");
+                }
+            } else {
+                let _ = tree.remove(&value);
+            }
+            peak_len = peak_len.max(tree.len());
+            // the arena only grows to the most values ever held at once
+            assert_eq!(tree.items.len(), peak_len);
+        }
+    }
+
+    #[test]
+    fn remove_all_equal_keys() {
+        let mut tree = Tree::new();
+        tree.set_duplicate_policy(DuplicatePolicy::Replace);
+        for payload in ["a", "b", "c"] {
+            let _ = tree.insert(Keyed(1, payload));
+        }
+        assert!(tree.insert(Keyed(2, "d")));
+        assert_eq!(tree.remove_all(&Keyed(1, "")), 1);
+        assert!(!tree.contains(&Keyed(1, "")));
+        assert_eq!(tree.remove_all(&Keyed(1, "")), 0);
+        assert_eq!(tree.len(), 1);
+
+        let mut multi = MultiTree::new();
+        for value in [4, 4, 7, 4, 7] {
+            assert!(multi.insert(value));
+        }
+        assert_eq!(multi.remove_all(&4), 3);
+        assert_eq!(multi.count(&4), 0);
+        assert_eq!(multi.len(), 2);
+        assert_eq!(multi.remove_all(&4), 0);
+        assert_eq!(multi.distinct_len(), 1);
+    }
+
+    #[test]
+    fn boxed_str_keys() {
+        let mut tree: Tree<Box<str>> = Tree::new();
+        for word in ["pear", "apple", "fig", "banana"] {
+            assert!(tree.insert_boxed(word));
+        }
+        assert!(!tree.insert_boxed(String::from("fig")));
+        assert!(tree.insert(Box::from("cherry")));
+
+        assert!(tree.contains(&Box::from("apple")));
+        assert!(!tree.contains(&Box::from("grape")));
+        assert!(tree.remove(&Box::from("pear")));
+        let words: Vec<&str> = tree.iter().map(|word| &**word).collect();
+        assert_eq!(words, vec!["apple", "banana", "cherry", "fig"]);
+        assert!(tree.check_invariants().is_ok());
+
+        let mut numbers: Tree<Box<i32>> = Tree::new();
+        assert!(numbers.insert_boxed(3));
+        assert!(numbers.contains(&Box::new(3)));
+    }
+
+    // cargo test --release --features fuzz arena_fuzz
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn arena_fuzz() {
+        use rand::{Rng, SeedableRng};
+        use std::collections::BTreeSet;
+
+        // small key domains force many rejected duplicates and constant slot reuse
+        for (seed, domain) in [(1, 4), (2, 16), (3, 64)] {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut tree = Tree::new();
+            let mut reference = BTreeSet::new();
+            for step in 0..1_000_000 {
+                let value: u32 = rng.random_range(0..domain);
+                match rng.random_range(0..6) {
+                    0 | 1 => assert_eq!(tree.insert(value), reference.insert(value)),
+                    2 | 3 => assert_eq!(tree.remove(&value), reference.remove(&value)),
+                    4 => assert_eq!(tree.take_min(), reference.pop_first()),
+                    _ => assert_eq!(tree.take_max(), reference.pop_last()),
+                }
+                if let Err(message) = tree.check_invariants() {
+                    panic!("seed {seed}, step {step}: {message}");
+                }
+                assert_eq!(tree.len(), reference.len());
+                assert!(tree.len() + tree.free_slots() <= tree.capacity());
+            }
+            assert!(tree.iter().eq(reference.iter()));
+        }
+    }
+
+    #[test]
+    fn map_monotonic_and_not() {
+        let mut tree = Tree::new();
+        tree.extend([3, 1, 4, 5, 9, 2, 6]);
+
+        let doubled = tree.map(|x| x * 2);
+        assert_eq!(doubled.to_sorted_vec(), vec![&2, &4, &6, &8, &10, &12, &18]);
+        assert!(doubled.check_invariants().is_ok());
+        assert_eq!(tree.map_sorted(|x| x * 2), doubled);
+
+        let negated = tree.map(|x| -x);
+        assert_eq!(
+            negated.to_sorted_vec(),
+            vec![&-9, &-6, &-5, &-4, &-3, &-2, &-1]
+        );
+        assert!(negated.check_invariants().is_ok());
+
+        let halves = tree.map(|x| x / 2);
+        assert_eq!(halves.to_sorted_vec(), vec![&0, &1, &2, &3, &4]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "from_sorted expects ascending values")]
+    fn map_sorted_rejects_non_monotonic() {
+        let mut tree = Tree::new();
+        tree.extend([1, 2, 3]);
+        let _ = tree.map_sorted(|x| -x);
+    }
+
+    #[test]
+    fn split_at_rank_sizes() {
+        for k in [0, 1, 17, 50, 99, 100, 150] {
+            let mut tree = Tree::new();
+            tree.extend((0..100).map(|i| i * 3));
+            let rest = tree.split_at_rank(k);
+
+            let kept = k.min(100);
+            assert_eq!(tree.len(), kept);
+            assert_eq!(rest.len(), 100 - kept);
+            assert!(tree.check_invariants().is_ok());
+            assert!(rest.check_invariants().is_ok());
+            assert!(tree.iter().copied().eq((0..kept).map(|i| i * 3)));
+            assert!(rest.iter().copied().eq((kept..100).map(|i| i * 3)));
+        }
+    }
+
+    #[test]
+    fn tree_by_reverse_order() {
+        let mut tree = TreeBy::with_cmp(|a: &i32, b: &i32| b.cmp(a));
+        for value in [5, 1, 9, 3, 7] {
+            assert!(tree.insert(value));
+        }
+        assert!(!tree.insert(3));
+        assert!(tree.iter().copied().eq([9, 7, 5, 3, 1]));
+        assert!(tree.contains(&7));
+        assert!(tree.remove(&7));
+        assert!(!tree.contains(&7));
+        assert_eq!(tree.len(), 4);
+    }
+
+    #[test]
+    fn tree_by_field() {
+        // order by name length only, so equal lengths count as duplicates
+        let mut tree = TreeBy::with_cmp(|a: &&str, b: &&str| a.len().cmp(&b.len()));
+        for name in ["ann", "bo", "carla", "dan"] {
+            let _ = tree.insert(name);
+        }
+        assert_eq!(tree.get(&"xyz"), Some(&"ann"));
+        assert!(tree.iter().copied().eq(["bo", "ann", "carla"]));
+
+        let mut many = TreeBy::with_cmp(|a: &u32, b: &u32| b.cmp(a));
+        for value in 0..1000 {
+            assert!(many.insert(value));
+        }
+        assert!(many.height() <= 10);
+        assert!(many.iter().copied().eq((0..1000).rev()));
+    }
+
+    #[test]
+    fn tree_by_without_ord() {
+        // f64 has no Ord, the stored values are only ever compared through the closure
+        let mut tree = TreeBy::with_cmp(|a: &f64, b: &f64| a.total_cmp(b));
+        for value in [2.5, -1.0, 0.25, 10.0] {
+            assert!(tree.insert(value));
+        }
+        assert!(tree.remove(&0.25));
+        assert!(tree.iter().copied().eq([-1.0, 2.5, 10.0]));
+    }
+
+    #[test]
+    fn iter_from_resumes() {
+        let mut tree = Tree::new();
+        tree.extend((0..20).map(|i| i * 5));
+
+        assert!(tree.iter_from(&45).copied().eq((9..20).map(|i| i * 5)));
+        assert!(
+            tree.iter_from_exclusive(&45)
+                .copied()
+                .eq((10..20).map(|i| i * 5))
+        );
+        // a missing key resumes at the next larger one either way
+        assert!(tree.iter_from(&47).copied().eq((10..20).map(|i| i * 5)));
+        assert!(
+            tree.iter_from_exclusive(&47)
+                .copied()
+                .eq((10..20).map(|i| i * 5))
+        );
+        assert!(tree.iter_from(&-1).copied().eq(tree.iter().copied()));
+        assert_eq!(tree.iter_from(&100).next(), None);
+        assert_eq!(tree.iter_from_exclusive(&95).next(), None);
+
+        // paging: each page resumes after the last value of the previous one
+        let mut pages = Vec::new();
+        let mut last = None;
+        loop {
+            let page: Vec<i32> = match last {
+                None => tree.iter().take(6).copied().collect(),
+                Some(last) => tree.iter_from_exclusive(&last).take(6).copied().collect(),
+            };
+            let Some(&end) = page.last() else { break };
+            last = Some(end);
+            pages.push(page);
+        }
+        assert_eq!(pages.len(), 4);
+        assert!(pages.concat().iter().eq(tree.iter()));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Value changed through for_each_in_range_mut broke the tree order")]
+    fn for_each_in_range_mut_detects_broken_order() {
+        let mut tree = Tree::new();
+        tree.extend(0..10);
+        tree.for_each_in_range_mut(3..=3, |value| *value = 100);
+    }
+
+    // also run under Miri: cargo +nightly miri test range_mut_references_outlive_next
+    #[test]
+    fn range_mut_references_outlive_next() {
+        let mut tree = Tree::new();
+        tree.extend(0..64);
+        let values: Vec<&mut i32> = tree.range_mut(10..50).collect();
+        assert_eq!(values.len(), 40);
+        for value in values {
+            *value *= 2;
+        }
+        let expected: Vec<i32> = (0..64)
+            .map(|v| if (10..50).contains(&v) { v * 2 } else { v })
+            .collect();
+        assert!(tree.iter().copied().eq(expected));
+
+        let mut it = tree.range_mut(..);
+        let first = it.next().unwrap();
+        let second = it.next().unwrap();
+        drop(it);
+        *second = 1;
+        *first = -1;
+        assert!(tree.iter().take(3).eq(&[-1, 1, 2]));
+    }
+
+    #[test]
+    fn for_each_in_range_mut_bumps_only_the_range() {
+        let mut tree = Tree::new();
+        tree.extend((0..20).map(|i| i * 10));
+        tree.for_each_in_range_mut(50..=100, |value| *value += 1);
+        assert!(tree.range(50..=101).copied().eq([51, 61, 71, 81, 91, 101]));
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn locate_present_and_absent() {
+        let mut tree = Tree::new();
+        tree.extend([10, 20, 30, 40, 50]);
+
+        let at = |value| tree.locate(&value);
+        assert_eq!(
+            at(30),
+            Located {
+                exists: true,
+                prev: Some(&20),
+                next: Some(&40)
+            }
+        );
+        assert_eq!(
+            at(10),
+            Located {
+                exists: true,
+                prev: None,
+                next: Some(&20)
+            }
+        );
+        assert_eq!(
+            at(50),
+            Located {
+                exists: true,
+                prev: Some(&40),
+                next: None
+            }
+        );
+        assert_eq!(
+            at(35),
+            Located {
+                exists: false,
+                prev: Some(&30),
+                next: Some(&40)
+            }
+        );
+        assert_eq!(
+            at(5),
+            Located {
+                exists: false,
+                prev: None,
+                next: Some(&10)
+            }
+        );
+        assert_eq!(
+            at(55),
+            Located {
+                exists: false,
+                prev: Some(&50),
+                next: None
+            }
+        );
+        assert_eq!(
+            Tree::<i32>::new().locate(&1),
+            Located {
+                exists: false,
+                prev: None,
+                next: None
             }
-        } else {
-            self.root = y_link;
-        }
+        );
+    }
 
-        self.with_occupied_mut(y_index, |y| y.parent = x_parent);
+    #[test]
+    fn try_from_vec_unique_and_duplicate() {
+        let tree = Tree::try_from(vec![4, 2, 6, 1, 3, 5, 7]).unwrap();
+        assert!(tree.iter().copied().eq(1..=7));
+        assert!(tree.check_invariants().is_ok());
 
-        // fix y -> x
-        self.with_occupied_mut(x_index, |x| x.parent = y_link);
-        self.with_occupied_mut(y_index, |y| y.left = x_link);
+        let err = Tree::<i32>::try_from(vec![4, 2, 6, 2, 4]).unwrap_err();
+        assert_eq!(err, DuplicateError(2));
+        assert_eq!(err.to_string(), "duplicate value 2");
 
-        // fix x -> t2
-        self.with_occupied_mut(x_index, |x| x.right = t2_link);
-        if let Some(t2_index) = t2_link {
-            self.with_occupied_mut(t2_index, |t2| t2.parent = x_link);
-        }
-        y_link
+        let empty = Tree::<i32>::try_from(Vec::new()).unwrap();
+        assert!(empty.is_empty());
     }
 
-    fn update_ancestor_heights(&mut self, link: Option<usize>) {
-        let mut cur = link;
-        while let Some(index) = cur {
-            self.update_height(index);
-            cur = self.unwrap_occupied(index).parent;
+    #[test]
+    fn depth_of_balanced_seven() {
+        let tree = Tree::from_sorted((1..=7).collect());
+        assert_eq!(tree.depth_of(&4), Some(0));
+        assert_eq!(tree.depth_of(&2), Some(1));
+        assert_eq!(tree.depth_of(&6), Some(1));
+        for leaf in [1, 3, 5, 7] {
+            assert_eq!(tree.depth_of(&leaf), Some(2));
         }
+        assert_eq!(tree.depth_of(&8), None);
+        assert_eq!(Tree::<i32>::new().depth_of(&1), None);
     }
 
-    fn update_height(&mut self, index: usize) {
-        let node = self.unwrap_occupied(index);
-        let left_height = self.link_height(node.left);
-        let right_height = self.link_height(node.right);
-
-        let node = self.unwrap_occupied_mut(index);
-        node.height = 1 + left_height.max(right_height);
-    }
+    #[test]
+    fn pop_first_last_alternating() {
+        let mut tree = Tree::new();
+        tree.extend(0..101);
+        let capacity = tree.capacity();
 
-    fn balance_factor(&self, link: Option<usize>) -> i32 {
-        if let Some(index) = link {
-            let node = self.unwrap_occupied(index);
-            let left_height = self.link_height(node.left);
-            let right_height = self.link_height(node.right);
-            return left_height - right_height;
-        } else {
-            0
+        let mut popped = Vec::new();
+        let mut from_front = true;
+        while !tree.is_empty() {
+            let value = if from_front {
+                tree.pop_first()
+            } else {
+                tree.pop_last()
+            };
+            popped.push(value.unwrap());
+            from_front = !from_front;
+            assert!(tree.check_invariants().is_ok());
         }
+        let expected: Vec<i32> = (0..=50).flat_map(|i| [i, 100 - i]).take(101).collect();
+        assert_eq!(popped, expected);
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+        assert_eq!(tree.capacity(), capacity);
     }
 
-    fn link_height(&self, link: Option<usize>) -> i32 {
-        match link {
-            Some(index) => self.unwrap_occupied(index).height,
-            None => -1,
-        }
-    }
+    #[test]
+    fn range_to_vec_matches_filtered_reference() {
+        let values: Vec<String> = (0..50).map(|i| format!("key{:03}", i * 7 % 50)).collect();
+        let mut tree = Tree::new();
+        tree.extend(values.iter().cloned());
 
-    fn before(&self, index: usize) -> Option<usize> {
-        self.before_sub(index).or(self.before_above(index))
+        let mut reference = values.clone();
+        reference.sort();
+        let low = String::from("key010");
+        let high = String::from("key030");
+        let expected: Vec<String> = reference
+            .iter()
+            .filter(|v| **v >= low && **v < high)
+            .cloned()
+            .collect();
+        assert_eq!(tree.range_to_vec(low.clone()..high.clone()), expected);
+        assert_eq!(tree.range_to_vec(..), reference);
+        assert!(tree.range_to_vec(high..low).is_empty());
     }
 
-    fn before_sub(&self, index: usize) -> Option<usize> {
-        let node = self.unwrap_occupied(index);
-        let Some(mut cur) = node.left else {
-            return None;
-        };
+    #[test]
+    fn fused_insert_retrace_keeps_invariants() {
+        use rand::SeedableRng;
+        use rand::seq::SliceRandom;
 
-        while let Some(right) = self.unwrap_occupied(cur).right {
-            cur = right;
+        let mut ascending = Tree::new();
+        let mut descending = Tree::new();
+        for i in 0..2047 {
+            assert!(ascending.insert(i));
+            assert!(descending.insert(2046 - i));
         }
-        Some(cur)
-    }
+        // sequential inserts into an AVL tree end up perfectly balanced at 2^k - 1 nodes
+        assert_eq!(ascending.height(), 10);
+        assert_eq!(descending.height(), 10);
+        assert!(ascending.check_invariants().is_ok());
+        assert!(descending.check_invariants().is_ok());
 
-    fn before_above(&self, index: usize) -> Option<usize> {
-        let node = self.unwrap_occupied(index);
-        let mut cur_index = index;
-        let mut cur = node;
-        while let Some(parent_index) = cur.parent {
-            let parent = self.unwrap_occupied(parent_index);
-            if parent.left == Some(cur_index) {
-                cur_index = parent_index;
-                cur = parent;
-            } else {
-                return cur.parent;
+        let mut values: Vec<i32> = (0..5000).collect();
+        values.shuffle(&mut rand::rngs::StdRng::seed_from_u64(893));
+        let mut shuffled = Tree::new();
+        for (count, value) in values.into_iter().enumerate() {
+            assert!(shuffled.insert(value));
+            if count % 97 == 0 {
+                assert!(shuffled.check_invariants().is_ok());
             }
         }
-        None
-    }
-
-    fn after(&self, index: usize) -> Option<usize> {
-        self.after_sub(index).or(self.after_above(index))
+        assert!(shuffled.check_invariants().is_ok());
+        assert!(shuffled.iter().copied().eq(0..5000));
     }
 
-    fn after_sub(&self, index: usize) -> Option<usize> {
-        let node = self.unwrap_occupied(index);
-        let Some(mut cur) = node.right else {
-            return None;
-        };
+    #[cfg(feature = "stats")]
+    #[test]
+    fn insert_rotates_at_most_once() {
+        use rand::{Rng, SeedableRng};
 
-        while let Some(left) = self.unwrap_occupied(cur).left {
-            cur = left;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(894);
+        let mut tree = Tree::new();
+        for _ in 0..5000 {
+            let before = tree.rotation_count();
+            let _ = tree.insert(rng.random_range(0..10_000));
+            // a double rotation counts as two
+            assert!(tree.rotation_count() - before <= 2);
         }
-        Some(cur)
-    }
+        assert!(tree.check_invariants().is_ok());
 
-    fn after_above(&self, index: usize) -> Option<usize> {
-        let node = self.unwrap_occupied(index);
-        let mut cur_index = index;
-        let mut cur = node;
-        while let Some(parent_index) = cur.parent {
-            let parent = self.unwrap_occupied(parent_index);
-            if parent.right == Some(cur_index) {
-                cur_index = parent_index;
-                cur = parent;
-            } else {
-                return cur.parent;
-            }
+        let mut sequential = Tree::new();
+        for i in 0..1000 {
+            let before = sequential.rotation_count();
+            assert!(sequential.insert(i));
+            assert!(sequential.rotation_count() - before <= 1);
         }
-        None
+        assert!(sequential.check_invariants().is_ok());
     }
 
-    fn unwrap_occupied(&self, index: usize) -> &Node<T> {
-        match &self.items[index] {
-            Slot::Occupied { node } => node,
-            Slot::Free { .. } => panic!("Called unwrap_occupied on free slot"),
-        }
+    #[test]
+    fn insert_reporting_marks_duplicates() {
+        let mut tree = Tree::new();
+        tree.extend([2, 4]);
+        let report = tree.insert_reporting([1, 2, 3, 3, 4, 5]);
+        assert_eq!(report, vec![true, false, true, false, false, true]);
+        assert!(tree.iter().copied().eq(1..=5));
+        assert!(tree.insert_reporting(Vec::new()).is_empty());
     }
 
-    fn unwrap_occupied_mut(&mut self, index: usize) -> &mut Node<T> {
-        match &mut self.items[index] {
-            Slot::Occupied { node } => node,
-            Slot::Free { .. } => panic!("Called unwrap_occupied on free slot"),
-        }
+    #[test]
+    fn remove_reporting_two_children() {
+        let mut tree = Tree::from_sorted((1..=7).collect());
+        // 4 is the root with children 2 and 6, its predecessor 3 takes its place
+        assert_eq!(
+            tree.remove_reporting(&4),
+            Some(RemovalInfo {
+                removed: 4,
+                promoted: Some(&3)
+            })
+        );
+        assert_eq!(tree.depth_of(&3), Some(0));
+        assert_eq!(
+            tree.remove_reporting(&7),
+            Some(RemovalInfo {
+                removed: 7,
+                promoted: None
+            })
+        );
+        assert_eq!(tree.remove_reporting(&4), None);
+        assert!(tree.iter().copied().eq([1, 2, 3, 5, 6]));
+        assert!(tree.check_invariants().is_ok());
     }
 
-    fn with_occupied_mut<F>(&mut self, index: usize, f: F)
-    where
-        F: FnOnce(&mut Node<T>),
-    {
-        match &mut self.items[index] {
-            Slot::Occupied { node } => f(node),
-            Slot::Free { .. } => panic!("Called unwrap_occupied on free slot"),
-        }
+    #[test]
+    fn iter_rev_is_reverse_of_iter() {
+        let mut tree = Tree::new();
+        assert_eq!(tree.iter_rev().next(), None);
+        tree.extend([50, 20, 80, 10, 30, 70, 90, 60, 40]);
+        let forward: Vec<&i32> = tree.iter().collect();
+        let mut backward: Vec<&i32> = tree.iter_rev().collect();
+        backward.reverse();
+        assert_eq!(forward, backward);
+        assert_eq!(tree.iter_rev().len(), 9);
     }
-}
 
-impl<T: Ord> Iterator for IntoIter<T> {
-    type Item = T;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.tree.first().map(|ptr| self.tree.remove_node(ptr))
+    #[test]
+    fn is_balanced_detects_lopsided_tree() {
+        let mut tree = Tree::from_sorted((1..=7).collect());
+        assert!(tree.is_balanced());
+        // cut off the root's left subtree, leaving right height 1 against left height -1;
+        // the detached slots stay in the arena and are dropped with it
+        let root = tree.root.unwrap();
+        tree.node_mut(root).left = None;
+        assert!(!tree.is_balanced());
+        assert!(!tree.stats().is_balanced);
     }
-}
 
-impl<'a, T: Ord> Iterator for Iter<'a, T> {
-    type Item = &'a T;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.next.map(|index| {
-            let node = self.tree.unwrap_occupied(index);
-            self.next = self.tree.after(index);
-            &node.value
-        })
-    }
-}
+    #[test]
+    fn clone_from_reuses_capacity() {
+        let mut source = Tree::new();
+        source.extend(0..100);
+        assert!(source.remove(&50));
 
-impl<T: Ord> Drop for Tree<T> {
-    fn drop(&mut self) {
-        while let Some(ptr) = self.root {
-            self.remove_node(ptr);
-        }
+        let copy = source.clone();
+        assert_eq!(copy, source);
+        assert!(copy.check_invariants().is_ok());
+
+        let mut target = Tree::new();
+        target.extend(1000..1500);
+        let capacity = target.capacity();
+        target.clone_from(&source);
+        assert_eq!(target, source);
+        assert!(target.check_invariants().is_ok());
+        assert_eq!(target.capacity(), capacity);
+
+        // the copy is independent of the source
+        assert!(target.insert(50));
+        assert!(!source.contains(&50));
     }
-}
 
-impl<T: Ord> Node<T> {
-    pub fn new(value: T) -> Self {
-        Node {
-            value,
-            height: 0,
-            parent: None,
-            left: None,
-            right: None,
+    #[test]
+    fn entry_occupied_and_vacant() {
+        let mut tree = Tree::new();
+        assert!(tree.insert(Keyed(1, "one")));
+        assert!(tree.insert(Keyed(3, "three")));
+
+        let stored = tree
+            .entry(Keyed(1, "ignored"))
+            .and_modify(|keyed| keyed.1 = "uno")
+            .or_insert();
+        assert_eq!(stored.1, "uno");
+
+        let inserted = tree
+            .entry(Keyed(2, "two"))
+            .and_modify(|keyed| keyed.1 = "not called")
+            .or_insert();
+        assert_eq!(inserted.1, "two");
+        inserted.1 = "dos";
+
+        match tree.entry(Keyed(3, "")) {
+            Entry::Occupied(entry) => {
+                assert_eq!(entry.get().1, "three");
+                assert_eq!(entry.remove().1, "three");
+            }
+            Entry::Vacant(_) => panic!("3 should be stored"),
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let labels: Vec<&str> = tree.iter().map(|keyed| keyed.1).collect();
+        assert_eq!(labels, vec!["uno", "dos"]);
+        assert!(tree.check_invariants().is_ok());
+    }
 
+    #[cfg(debug_assertions)]
     #[test]
-    fn empty_after_creation() {
-        let tree = Tree::<i32>::new();
-        assert_eq!(tree.len(), 0);
+    #[should_panic(expected = "Value changed through an entry broke the tree order")]
+    fn entry_modify_detects_broken_order() {
+        let mut tree = Tree::new();
+        tree.extend([1, 2, 3]);
+        let _ = tree.entry(1).and_modify(|value| *value = 5);
     }
 
     #[test]
-    fn insert_and_contains() {
-        let mut tree = Tree::<i32>::new();
-        for i in 0..10 {
-            assert_eq!(tree.len(), i as usize);
-            tree.insert(i);
-            assert!(tree.contains(&i));
+    fn rebalance_off_degrades_to_list() {
+        let mut plain = Tree::new();
+        plain.set_rebalance(false);
+        let mut avl = Tree::new();
+        for i in 0..100 {
+            assert!(plain.insert(i));
+            assert!(avl.insert(i));
         }
-        for i in 0..10 {
-            assert!(tree.contains(&i));
+        assert_eq!(plain.height(), 99);
+        assert_eq!(avl.height(), 6);
+        assert!(!plain.is_balanced());
+        assert!(avl.is_balanced());
+
+        // removals keep the order and heights correct, just not the balance
+        for i in (0..100).step_by(3) {
+            assert!(plain.remove(&i));
         }
-        assert!(!tree.contains(&100));
+        assert!(plain.iter().copied().eq((0..100).filter(|i| i % 3 != 0)));
+        assert_eq!(plain.height(), 65);
+
+        plain.rebuild();
+        assert!(plain.is_balanced());
+        assert!(plain.check_invariants().is_ok());
     }
 
     #[test]
-    fn insert_large_logarithmic_height() {
-        let mut tree = Tree::<i32>::new();
-        let size = 1000;
-        for i in 0..size {
-            assert_eq!(tree.len(), i as usize);
-            tree.insert(i);
-            assert!(tree.contains(&i));
-        }
-        for i in 0..size {
-            assert!(tree.contains(&i));
-        }
+    fn iter_with_depth_balanced_seven() {
+        let tree = Tree::from_sorted((1..=7).collect());
+        let pairs: Vec<(usize, i32)> = tree
+            .iter_with_depth()
+            .map(|(depth, &value)| (depth, value))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![(2, 1), (1, 2), (2, 3), (0, 4), (2, 5), (1, 6), (2, 7)]
+        );
+        assert_eq!(Tree::<i32>::new().iter_with_depth().next(), None);
 
-        assert_eq!(tree.height(), size.ilog2() as i32);
+        let mut shuffled = Tree::new();
+        shuffled.extend([40, 10, 90, 30, 70, 20, 60, 80, 50]);
+        for (depth, value) in shuffled.iter_with_depth() {
+            assert_eq!(shuffled.depth_of(value), Some(depth));
+        }
     }
 
     #[test]
-    fn remove_and_contains() {
-        let mut tree = Tree::<i32>::new();
-        for i in 0..10 {
-            tree.insert(i);
-        }
-        for i in 0..10 {
-            assert!(tree.contains(&i));
+    fn partition_point_is_ceiling() {
+        let mut tree = Tree::new();
+        tree.extend((0..30).map(|i| i * 3));
+        for threshold in -2..92 {
+            let (_, ceiling) = tree.floor_ceiling(&threshold);
+            assert_eq!(tree.partition_point(|x| *x >= threshold), ceiling);
         }
-        for i in 0..10 {
-            for j in 0..i {
-                assert_eq!(tree.contains(&j), false);
-            }
-            for j in i..10 {
-                assert_eq!(tree.contains(&j), true);
-            }
+        assert_eq!(tree.partition_point(|_| true), Some(&0));
+        assert_eq!(tree.partition_point(|_| false), None);
+        assert_eq!(Tree::<i32>::new().partition_point(|_| true), None);
+    }
 
-            let removed = tree.remove(&i);
-            assert_eq!(removed, true);
+    #[test]
+    fn tree_arena_trees_are_independent() {
+        use rand::{Rng, SeedableRng};
 
-            for j in 0..i + 1 {
-                assert_eq!(tree.contains(&j), false);
-            }
-            for j in i + 1..10 {
-                assert_eq!(tree.contains(&j), true);
+        let mut arena = TreeArena::new();
+        let ids: Vec<TreeId> = (0..8).map(|_| arena.add_tree()).collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(907);
+        let mut expected = vec![std::collections::BTreeSet::new(); ids.len()];
+        for _ in 0..4000 {
+            let which = rng.random_range(0..ids.len());
+            let value = rng.random_range(0..100);
+            if rng.random_range(0..3) == 0 {
+                assert_eq!(
+                    arena.remove(ids[which], &value),
+                    expected[which].remove(&value)
+                );
+            } else {
+                assert_eq!(
+                    arena.insert(ids[which], value),
+                    expected[which].insert(value)
+                );
             }
         }
+        for (id, set) in ids.iter().zip(&expected) {
+            assert_eq!(arena.len(*id), set.len());
+            assert!(arena.iter(*id).eq(set.iter()));
+            assert!(set.iter().all(|value| arena.contains(*id, value)));
+        }
+
+        // a cleared tree's slots are reused by the others without growing the arena
+        let capacity = arena.capacity();
+        let freed = arena.len(ids[0]);
+        arena.clear(ids[0]);
+        assert!(arena.is_empty(ids[0]));
+        assert_eq!(arena.iter(ids[0]).next(), None);
+        for value in 1000..1000 + freed as i32 {
+            assert!(arena.insert(ids[1], value));
+        }
+        assert_eq!(arena.capacity(), capacity);
+        assert_eq!(arena.len(ids[2]), expected[2].len());
     }
 
     #[test]
-    fn first_after_asc_insert() {
+    fn values_in_sorted_order() {
         let mut tree = Tree::new();
-        for i in 0..10 {
-            tree.insert(i);
+        for value in [5, 3, 8, 1, 4] {
+            assert!(tree.insert(value));
         }
+        assert!(tree.values().eq(tree.iter()));
         assert_eq!(
-            tree.first().map(|index| tree.unwrap_occupied(index).value),
-            Some(0)
+            tree.values().copied().collect::<Vec<_>>(),
+            vec![1, 3, 4, 5, 8]
         );
+        assert_eq!(tree.into_values().collect::<Vec<_>>(), vec![1, 3, 4, 5, 8]);
     }
 
+    #[cfg(feature = "checked")]
     #[test]
-    fn first_after_desc_insert() {
+    fn checked_accepts_normal_operations() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(909);
         let mut tree = Tree::new();
-        for i in (0..10).rev() {
-            tree.insert(i);
+        for _ in 0..2000 {
+            let value = rng.random_range(0..200);
+            if rng.random_bool(0.4) {
+                let _ = tree.remove(&value);
+            } else {
+                let _ = tree.insert(value);
+            }
         }
-        assert_eq!(
-            tree.first().map(|index| tree.unwrap_occupied(index).value),
-            Some(0)
-        );
+
+        let mut plain = Tree::new();
+        plain.set_rebalance(false);
+        for value in 0..50 {
+            assert!(plain.insert(value));
+        }
+        assert!(plain.remove(&25));
+
+        // every mutator goes through the same checked exits
+        let first = tree.iter().next().copied();
+        assert_eq!(tree.pop_first(), first);
+        let _ = tree.take_max();
+        let _ = tree.entry(500).or_insert();
+        let _ = tree.remove_reporting(&500);
+        let rest = tree.split_at_rank(tree.len() / 2);
+        assert!(Tree::try_from(rest.into_sorted_vec()).is_ok());
+
+        let mut arena = TreeArena::new();
+        let ids = [arena.add_tree(), arena.add_tree()];
+        for value in 0..100 {
+            assert!(arena.insert(ids[value % 2], value));
+        }
+        assert!(arena.remove(ids[0], &50));
+
+        let mut by = TreeBy::with_cmp(|a: &i32, b: &i32| b.cmp(a));
+        for value in 0..100 {
+            assert!(by.insert(value));
+        }
+        assert!(by.remove(&50));
     }
 
+    #[cfg(feature = "checked")]
     #[test]
-    fn into_iter_asc() {
+    #[should_panic(expected = "Tree invariants broken after insert")]
+    fn checked_panics_on_corruption() {
         let mut tree = Tree::new();
-        for i in 0..10 {
-            tree.insert(i);
+        for value in 1..=10 {
+            assert!(tree.insert(value));
         }
+        let last = tree.last().unwrap();
+        tree.node_mut(last).value = 0;
+        let _ = tree.insert(11);
+    }
 
-        let mut iter = tree.into_iter();
-        for i in 0..10 {
-            assert_eq!(iter.next(), Some(i));
-        }
-        assert_eq!(iter.next(), None);
+    #[cfg(feature = "checked")]
+    #[test]
+    #[should_panic(expected = "Tree invariants broken after remove")]
+    fn checked_covers_pop_first() {
+        let mut tree = Tree::new();
+        tree.extend(1..=10);
+        let last = tree.last().unwrap();
+        tree.node_mut(last).value = 0;
+        let _ = tree.pop_first();
     }
 
     #[test]
-    fn into_iter_desc() {
+    fn fragmentation_tracks_free_slots() {
         let mut tree = Tree::new();
-        for i in (0..10).rev() {
-            tree.insert(i);
+        assert_eq!(tree.fragmentation(), 0.0);
+        for value in 0..1000 {
+            assert!(tree.insert(value));
+        }
+        assert_eq!(tree.fragmentation(), 0.0);
+        for value in (0..1000).step_by(2) {
+            assert!(tree.remove(&value));
         }
+        assert_eq!(tree.fragmentation(), 0.5);
+        assert_eq!(tree.free_slots(), 500);
 
-        let mut iter = tree.into_iter();
-        for i in 0..10 {
-            assert_eq!(iter.next(), Some(i));
+        // inserts take freed slots first, so the arena doesn't grow until they run out
+        for value in 1000..1250 {
+            assert!(tree.insert(value));
         }
-        assert_eq!(iter.next(), None);
+        assert_eq!(tree.fragmentation(), 0.25);
+        assert_eq!(tree.items.len(), 1000);
+
+        tree.shrink_to_fit();
+        assert_eq!(tree.fragmentation(), 0.0);
+        assert_eq!(tree.items.len(), tree.len());
+        assert_eq!(tree.check_invariants(), Ok(()));
+        assert!(
+            tree.iter()
+                .copied()
+                .eq((1..1000).step_by(2).chain(1000..1250))
+        );
     }
 
     #[test]
-    fn iter_asc() {
+    fn shrink_policy_applies_to_every_removal() {
         let mut tree = Tree::new();
-        for i in 0..10 {
-            tree.insert(i);
+        tree.set_shrink_policy(Some(0.25));
+        for value in 0..1000 {
+            assert!(tree.insert(value));
+        }
+        for value in (0..1000).rev().step_by(3) {
+            match value % 4 {
+                0 => assert!(tree.remove(&value)),
+                1 => assert_eq!(tree.remove_all(&value), 1),
+                2 => {
+                    let node_ref = tree.insert_ref(value);
+                    assert_eq!(tree.remove_ref(node_ref), Some(value));
+                }
+                _ => {
+                    let info = tree.remove_reporting(&value).unwrap();
+                    assert_eq!(info.removed, value);
+                    if let Some(promoted) = info.promoted {
+                        assert!(*promoted < value);
+                    }
+                }
+            }
+            assert!(tree.fragmentation() <= 0.25);
+            let _ = tree.pop_last();
+            assert!(tree.fragmentation() <= 0.25);
         }
+        assert!(tree.items.len() < 1000);
+        assert_eq!(tree.check_invariants(), Ok(()));
 
-        let mut iter = tree.iter();
-        for i in 0..10 {
-            assert_eq!(iter.next(), Some(&i));
+        tree.set_shrink_policy(None);
+        for value in (0..1000).step_by(2) {
+            let _ = tree.remove(&value);
         }
-        assert_eq!(iter.next(), None);
+        assert!(tree.fragmentation() > 0.25);
     }
 
     #[test]
-    fn iter_desc() {
+    #[should_panic(expected = "Shrink threshold must be in (0, 1]")]
+    fn shrink_policy_rejects_zero() {
+        Tree::<i32>::new().set_shrink_policy(Some(0.0));
+    }
+
+    #[test]
+    fn shrink_policy_rejects_nan() {
+        let result =
+            std::panic::catch_unwind(|| Tree::<i32>::new().set_shrink_policy(Some(f64::NAN)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn degenerate_tree_checks_without_recursion() {
+        // a left spine, as descending inserts build with rebalancing off; one stack frame per
+        // node would overflow the test thread on a chain this long
+        let n: usize = 200_000;
         let mut tree = Tree::new();
-        for i in (0..10).rev() {
-            tree.insert(i);
+        tree.set_rebalance(false);
+        tree.items = (0..n)
+            .map(|i| Slot::Occupied {
+                node: Node {
+                    value: n - 1 - i,
+                    height: (n - 1 - i) as i32,
+                    generation: 0,
+                    parent: i.checked_sub(1),
+                    left: (i + 1 < n).then_some(i + 1),
+                    right: None,
+                },
+            })
+            .collect();
+        tree.root = Some(0);
+        tree.len = n;
+        assert_eq!(tree.check_subtree(tree.root, false), Ok((n as i32 - 1, n)));
+        assert!(!tree.is_balanced());
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(i32),
+        Remove(i32),
+        Contains(i32),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // a small key domain makes duplicate inserts and removals of present keys common
+        prop_oneof![
+            (0..64).prop_map(Op::Insert),
+            (0..64).prop_map(Op::Remove),
+            (0..64).prop_map(Op::Contains),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn matches_btreeset(ops in prop::collection::vec(op(), 0..300)) {
+            let mut tree = Tree::new();
+            let mut reference = BTreeSet::new();
+            for op in ops {
+                match op {
+                    Op::Insert(value) => prop_assert_eq!(tree.insert(value), reference.insert(value)),
+                    Op::Remove(value) => prop_assert_eq!(tree.remove(&value), reference.remove(&value)),
+                    Op::Contains(value) => {
+                        prop_assert_eq!(tree.contains(&value), reference.contains(&value))
+                    }
+                }
+                prop_assert_eq!(tree.len(), reference.len());
+                prop_assert_eq!(tree.check_invariants(), Ok(()));
+            }
+            prop_assert!(tree.iter().eq(reference.iter()));
         }
 
-        let mut iter = tree.iter();
-        for i in 0..10 {
-            assert_eq!(iter.next(), Some(&i));
+        #[test]
+        fn set_relations_match_btreeset(
+            a in prop::collection::btree_set(0..32, 0..20),
+            b in prop::collection::btree_set(0..32, 0..20),
+        ) {
+            let mut tree_a = Tree::new();
+            let mut tree_b = Tree::new();
+            a.iter().for_each(|&v| { let _ = tree_a.insert(v); });
+            b.iter().for_each(|&v| { let _ = tree_b.insert(v); });
+            prop_assert_eq!(tree_a.is_subset(&tree_b), a.is_subset(&b));
+            prop_assert_eq!(tree_a.is_superset(&tree_b), a.is_superset(&b));
+            prop_assert_eq!(tree_a.is_disjoint(&tree_b), a.is_disjoint(&b));
+            prop_assert!(tree_a.is_subset(&tree_a));
         }
-        assert_eq!(iter.next(), None);
     }
 }
@@ -1,5 +1,14 @@
-use clap::Parser;
-use learn_rust_ds::{avl_hashmap, avl_unsafe, avl_vec, tree};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use learn_rust_ds::tree::{TreeOps, TreeStats};
+use learn_rust_ds::{avl_vec, tree};
+use rand::distr::{Alphanumeric, SampleString};
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
 use std::time::Instant;
 
 #[derive(Parser)]
@@ -11,41 +20,147 @@ struct Args {
 
     #[arg(long, default_value = "unsafe")]
     tree: String,
+
+    #[arg(long, value_enum, default_value_t = KeyType::Usize)]
+    key_type: KeyType,
+
+    /// Write the shape of an avl_vec tree with the same keys to a DOT file (--size up to 1000)
+    #[arg(long)]
+    emit_dot: Option<PathBuf>,
+
+    /// Repeat the workload counting value comparisons per operation
+    #[cfg(feature = "stats")]
+    #[arg(long)]
+    count_comparisons: bool,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum KeyType {
+    Usize,
+    U64,
+    /// Random alphanumeric strings, where comparisons cost more than for integers
+    String,
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeyType::Usize => "usize",
+            KeyType::U64 => "u64",
+            KeyType::String => "string",
+        };
+        f.write_str(name)
+    }
+}
+
+// one DOT node per key, and Graphviz gets unreadable long before this
+const MAX_DOT_SIZE: usize = 1000;
+
 fn main() {
     let args = Args::parse();
-    let mut tree: Box<dyn tree::TreeOps<usize>> = match args.tree.as_str() {
-        "hashmap" => Box::new(avl_hashmap::Tree::new()),
-        "unsafe" => Box::new(avl_unsafe::Tree::new()),
-        "vec" => Box::new(avl_vec::Tree::new()),
-        _ => panic!("Unexpected value for tree: {}", args.tree),
-    };
-
+    if args.emit_dot.is_some() && args.size > MAX_DOT_SIZE {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("--emit-dot needs --size of at most {MAX_DOT_SIZE}"),
+            )
+            .exit();
+    }
     println!(
-        "Running with {} tree and {} node count",
-        args.tree, args.size
+        "Running with {} tree, {} keys and {} node count",
+        args.tree, args.key_type, args.size
     );
 
     let size = args.size;
+    // only even keys are stored so that odd keys miss in between them
+    match args.key_type {
+        KeyType::Usize => run(
+            &args,
+            (0..size).map(|i| 2 * i).collect(),
+            (0..size).map(|i| 2 * i + 1).collect(),
+        ),
+        KeyType::U64 => run(
+            &args,
+            (0..size as u64).map(|i| 2 * i).collect(),
+            (0..size as u64).map(|i| 2 * i + 1).collect(),
+        ),
+        KeyType::String => {
+            let (present, missing) = random_strings(size);
+            run(&args, present, missing)
+        }
+    }
+}
+
+// 2 * size distinct strings of 8 to 24 characters, split into stored and missing halves
+fn random_strings(size: usize) -> (Vec<String>, Vec<String>) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut seen = HashSet::with_capacity(2 * size);
+    let mut strings = Vec::with_capacity(2 * size);
+    while strings.len() < 2 * size {
+        let len = rng.random_range(8..=24);
+        let value = Alphanumeric.sample_string(&mut rng, len);
+        if seen.insert(value.clone()) {
+            strings.push(value);
+        }
+    }
+    let missing = strings.split_off(size);
+    (strings, missing)
+}
+
+fn run<T: Ord + Clone + fmt::Display + 'static>(args: &Args, present: Vec<T>, missing: Vec<T>) {
+    time_workload(&args.tree, &present, &missing);
+
+    #[cfg(feature = "stats")]
+    if args.count_comparisons {
+        count_comparisons(&args.tree, &present, &missing);
+    }
+
+    if let Some(path) = &args.emit_dot {
+        let mut dot_tree = avl_vec::Tree::new();
+        for key in &present {
+            let _ = dot_tree.insert(key.clone());
+        }
+        fs::write(path, dot_tree.to_dot()).expect("Failed to write DOT file");
+        println!("Wrote tree shape to {}", path.display());
+    }
+}
+
+fn time_workload<T: Ord + Clone + 'static>(kind: &str, present: &[T], missing: &[T]) {
+    let mut tree = make_tree::<T>(kind);
+    // insert takes its keys by value, so the copies used for insertion are made up front
+    let to_insert = present.to_vec();
+
+    // black_box keeps the optimizer from dropping calls whose result is unused, and the
+    // results are only checked once timing is done
     let start = Instant::now();
-    for i in 0..size {
-        tree.insert(i);
+    for key in to_insert {
+        black_box(tree.insert(black_box(key)));
     }
     let inserted = Instant::now();
-    for i in 0..size {
-        assert!(tree.contains(&i));
+    let after_inserts = tree.stats();
+    let mut all_found = true;
+    for key in present {
+        all_found &= tree.contains(black_box(key));
     }
     let checked_contains = Instant::now();
-    for i in 0..size {
-        tree.remove(&i);
+    let mut any_missing_found = false;
+    for key in missing {
+        any_missing_found |= tree.contains(black_box(key));
+    }
+    let checked_missing = Instant::now();
+    for key in present {
+        black_box(tree.remove(black_box(key)));
     }
     let end = Instant::now();
+    let after_removals = tree.stats();
+    assert!(black_box(all_found));
+    assert!(!black_box(any_missing_found));
 
     println!(
         "Inserts took {} ms",
         inserted.saturating_duration_since(start).as_micros() as f32 / 1000.0
     );
+    print_stats("After inserts", after_inserts);
     println!(
         "Checking contains took {} ms",
         checked_contains
@@ -53,12 +168,65 @@ fn main() {
             .as_micros() as f32
             / 1000.0
     );
+    println!(
+        "Checking missing contains took {} ms",
+        checked_missing
+            .saturating_duration_since(checked_contains)
+            .as_micros() as f32
+            / 1000.0
+    );
     println!(
         "Removals took {} ms",
-        end.saturating_duration_since(checked_contains).as_micros() as f32 / 1000.0
+        end.saturating_duration_since(checked_missing).as_micros() as f32 / 1000.0
     );
+    print_stats("After removals", after_removals);
     println!(
         "Total {} ms",
         end.saturating_duration_since(start).as_micros() as f32 / 1000.0
     );
 }
+
+fn make_tree<T: Ord + 'static>(kind: &str) -> Box<dyn TreeOps<T>> {
+    tree::make_tree::<T>(kind).unwrap_or_else(|| panic!("Unexpected value for tree: {kind}"))
+}
+
+fn print_stats(phase: &str, stats: TreeStats) {
+    println!(
+        "{phase}: len {}, height {}, balanced {}",
+        stats.len, stats.height, stats.is_balanced
+    );
+}
+
+// repeats the timed workload with every key wrapped in CountingKey, reporting average
+// comparisons per call
+#[cfg(feature = "stats")]
+fn count_comparisons<T: Ord + Clone + 'static>(kind: &str, present: &[T], missing: &[T]) {
+    use learn_rust_ds::tree::{CountingKey, take_comparison_count};
+
+    let present: Vec<CountingKey<T>> = present.iter().cloned().map(CountingKey).collect();
+    let missing: Vec<CountingKey<T>> = missing.iter().cloned().map(CountingKey).collect();
+    let mut tree = make_tree::<CountingKey<T>>(kind);
+    let report = |phase: &str, calls: usize| {
+        println!(
+            "{phase}: {:.2} comparisons per call",
+            take_comparison_count() as f64 / calls.max(1) as f64
+        );
+    };
+    take_comparison_count();
+    for key in &present {
+        let _ = tree.insert(key.clone());
+    }
+    report("Inserts", present.len());
+    for key in &present {
+        let _ = tree.contains(key);
+    }
+    report("Contains", present.len());
+    for key in &missing {
+        let _ = tree.contains(key);
+    }
+    report("Missing contains", missing.len());
+    for key in &present {
+        let _ = tree.remove(key);
+    }
+    report("Removals", present.len());
+}
@@ -0,0 +1,188 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle(usize);
+
+pub struct HandleHeap<T: Ord> {
+    values: Vec<(T, Handle)>,
+    // handle id -> current index in values, None once the value has left the heap
+    positions: Vec<Option<usize>>,
+}
+
+impl<T: Ord> HandleHeap<T> {
+    pub fn new() -> Self {
+        HandleHeap {
+            values: Vec::new(),
+            positions: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, value: T) -> Handle {
+        let handle = Handle(self.positions.len());
+        self.positions.push(Some(self.values.len()));
+        self.values.push((value, handle));
+        self.siftup(self.values.len() - 1);
+        handle
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.values.is_empty() {
+            return None;
+        }
+        Some(self.remove_at(0))
+    }
+
+    pub fn top(&self) -> Option<&T> {
+        self.values.first().map(|(value, _)| value)
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        let index = self.position(handle)?;
+        Some(&self.values[index].0)
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.position(handle).is_some()
+    }
+
+    // returns the previous value, or gives new_value back if the handle is stale
+    pub fn change_priority(&mut self, handle: Handle, new_value: T) -> Result<T, T> {
+        let Some(index) = self.position(handle) else {
+            return Err(new_value);
+        };
+        let old = std::mem::replace(&mut self.values[index].0, new_value);
+        let index = self.siftup(index);
+        self.siftdown(index);
+        Ok(old)
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let index = self.position(handle)?;
+        Some(self.remove_at(index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn position(&self, handle: Handle) -> Option<usize> {
+        self.positions.get(handle.0).copied().flatten()
+    }
+
+    fn remove_at(&mut self, index: usize) -> T {
+        let last = self.values.len() - 1;
+        self.swap(index, last);
+        let (value, handle) = self.values.pop().expect("Heap should not be empty");
+        self.positions[handle.0] = None;
+        if index < self.values.len() {
+            let index = self.siftup(index);
+            self.siftdown(index);
+        }
+        value
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.values.swap(a, b);
+        self.positions[self.values[a].1.0] = Some(a);
+        self.positions[self.values[b].1.0] = Some(b);
+    }
+
+    fn siftup(&mut self, mut index: usize) -> usize {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.values[parent].0 < self.values[index].0 {
+                self.swap(index, parent);
+                index = parent;
+            } else {
+                break;
+            }
+        }
+        index
+    }
+
+    fn siftdown(&mut self, mut index: usize) {
+        while index < self.values.len() {
+            let left_idx = 2 * index + 1;
+            let right_idx = 2 * index + 2;
+            if left_idx >= self.values.len() {
+                break;
+            }
+
+            let mut greater_idx = left_idx;
+            if right_idx < self.values.len() && self.values[left_idx].0 < self.values[right_idx].0 {
+                greater_idx = right_idx;
+            }
+
+            if self.values[index].0 < self.values[greater_idx].0 {
+                self.swap(index, greater_idx);
+                index = greater_idx;
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+impl<T: Ord> Default for HandleHeap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use super::HandleHeap;
+
+    #[test]
+    fn pop_order_without_changes() {
+        let mut heap = HandleHeap::new();
+        for i in [5, 1, 4, 2, 3] {
+            heap.push(i);
+        }
+        for i in (1..=5).rev() {
+            assert_eq!(heap.pop(), Some(i));
+        }
+        assert_eq!(heap.pop(), None);
+    }
+
+    #[test]
+    fn decrease_key_changes_pop_order() {
+        // min-heap of distances, as used by Dijkstra
+        let mut heap = HandleHeap::new();
+        let a = heap.push(Reverse(10));
+        let b = heap.push(Reverse(20));
+        let c = heap.push(Reverse(30));
+        let d = heap.push(Reverse(40));
+
+        assert_eq!(heap.change_priority(d, Reverse(5)), Ok(Reverse(40)));
+        assert_eq!(heap.change_priority(a, Reverse(25)), Ok(Reverse(10)));
+        assert_eq!(heap.get(d), Some(&Reverse(5)));
+
+        assert_eq!(heap.pop(), Some(Reverse(5)));
+        assert_eq!(heap.pop(), Some(Reverse(20)));
+        assert_eq!(heap.pop(), Some(Reverse(25)));
+        assert_eq!(heap.pop(), Some(Reverse(30)));
+        assert!(heap.is_empty());
+        assert!(!heap.contains(b));
+        assert!(!heap.contains(c));
+    }
+
+    #[test]
+    fn remove_by_handle() {
+        let mut heap = HandleHeap::new();
+        let handles: Vec<_> = (0..10).map(|i| heap.push(i)).collect();
+
+        assert_eq!(heap.remove(handles[9]), Some(9));
+        assert_eq!(heap.remove(handles[4]), Some(4));
+        assert_eq!(heap.remove(handles[4]), None);
+        assert_eq!(heap.change_priority(handles[4], 100), Err(100));
+        assert_eq!(heap.len(), 8);
+
+        let popped: Vec<i32> = std::iter::from_fn(|| heap.pop()).collect();
+        assert_eq!(popped, vec![8, 7, 6, 5, 3, 2, 1, 0]);
+    }
+}
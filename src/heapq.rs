@@ -1,18 +1,51 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::hash::{Hash, Hasher};
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+
+// Max-heap; a min-heap is a MinHeapQ, holding Reverse(value). Values that compare equal come
+// out in no particular order: pop is not stable, so pair values with an insertion counter
+// when ties have to keep their push order.
 pub struct HeapQ<T: Ord> {
     values: Vec<T>,
 }
 
+pub type MinHeapQ<T> = HeapQ<Reverse<T>>;
+
+// the direction is part of the built type, see min
+pub struct HeapQBuilder<T: Ord> {
+    capacity: Option<usize>,
+    _marker: PhantomData<T>,
+}
+
+// next pops the maximum in O(log n), next_back scans the leaves for the minimum in O(n)
+pub struct IntoIter<T: Ord> {
+    heap: HeapQ<T>,
+}
+
 impl<T: Ord> HeapQ<T> {
     pub fn new() -> Self {
         HeapQ { values: Vec::new() }
     }
 
-    pub fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
-        let mut heap = HeapQ {
-            values: iter.into_iter().collect(),
-        };
-        heap.heapify();
-        heap
+    pub fn builder() -> HeapQBuilder<T> {
+        HeapQBuilder {
+            capacity: None,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    pub fn from_binary_heap(heap: BinaryHeap<T>) -> Self {
+        Self::from(heap.into_vec())
+    }
+
+    pub fn into_binary_heap(self) -> BinaryHeap<T> {
+        BinaryHeap::from(self.values)
     }
 
     pub fn push(&mut self, value: T) {
@@ -20,6 +53,21 @@ impl<T: Ord> HeapQ<T> {
         self.siftup(self.values.len() - 1);
     }
 
+    // sifts each new value up when the batch is small, otherwise re-heapifies everything in O(n)
+    pub fn extend_from<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let old_len = self.values.len();
+        self.values.extend(iter);
+        let added = self.values.len() - old_len;
+        if added >= old_len {
+            self.heapify();
+        } else {
+            for index in old_len..self.values.len() {
+                self.siftup(index);
+            }
+        }
+    }
+
+    #[must_use]
     pub fn pop(&mut self) -> Option<T> {
         match self.values.len() {
             0 => None,
@@ -33,14 +81,71 @@ impl<T: Ord> HeapQ<T> {
         }
     }
 
+    // Like pop, but releases memory during a long drain: once fewer than a quarter of the
+    // slots are used the buffer is shrunk to fit. Each shrink copies the remaining values and
+    // the next one waits until a quarter of those are left, so the copies add O(1) amortized.
+    #[must_use]
+    pub fn pop_shrinking(&mut self) -> Option<T> {
+        let value = self.pop();
+        if self.values.len() < self.values.capacity() / 4 {
+            self.values.shrink_to_fit();
+        }
+        value
+    }
+
+    // empties the heap into out in pop order, descending for a max-heap; out is cleared
+    // first and keeps its allocation, so one buffer can serve repeated sorts
+    pub fn drain_sorted_into(&mut self, out: &mut Vec<T>) {
+        out.clear();
+        out.reserve(self.len());
+        while let Some(value) = self.pop() {
+            out.push(value);
+        }
+    }
+
+    // Heapsort in place: the top is swapped to the end of the shrinking heap and the new
+    // root sifted down, so the buffer is reused and nothing is pushed or popped. Like
+    // BinaryHeap::into_sorted_vec, a MinHeapQ's Reverse values come out ascending.
+    pub fn into_ascending_vec(mut self) -> Vec<T> {
+        for end in (1..self.values.len()).rev() {
+            self.values.swap(0, end);
+            self.siftdown_within(0, end);
+        }
+        self.values
+    }
+
+    // pops only when pred accepts the top, otherwise the heap is left as it was
+    pub fn pop_if<F: FnOnce(&T) -> bool>(&mut self, pred: F) -> Option<T> {
+        if pred(self.values.first()?) {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
     pub fn top(&mut self) -> Option<&T> {
-        self.values.get(0)
+        self.values.first()
+    }
+
+    // Yields values in pop order without modifying the heap. References keep the heap
+    // layout of the values, so they form a valid heap as they are and each step is O(log n).
+    pub fn iter_sorted(&self) -> impl Iterator<Item = &T> {
+        let mut refs = HeapQ {
+            values: self.values.iter().collect(),
+        };
+        std::iter::from_fn(move || refs.pop())
     }
 
     pub fn len(&self) -> usize {
         self.values.len()
     }
 
+    // every parent is >= both of its children
+    pub fn is_valid_heap(&self) -> bool {
+        (1..self.values.len()).all(|index| self.values[(index - 1) / 2] >= self.values[index])
+    }
+
     pub fn is_empty(&self) -> bool {
         self.values.is_empty()
     }
@@ -57,16 +162,21 @@ impl<T: Ord> HeapQ<T> {
         }
     }
 
-    fn siftdown(&mut self, mut index: usize) {
-        while index < self.values.len() {
+    fn siftdown(&mut self, index: usize) {
+        self.siftdown_within(index, self.values.len());
+    }
+
+    // treats values[end..] as outside the heap
+    fn siftdown_within(&mut self, mut index: usize, end: usize) {
+        while index < end {
             let left_idx = 2 * index + 1;
             let right_idx = 2 * index + 2;
-            if left_idx >= self.values.len() {
+            if left_idx >= end {
                 break;
             }
 
             let mut greater_idx = left_idx;
-            if right_idx < self.values.len() && self.values[left_idx] < self.values[right_idx] {
+            if right_idx < end && self.values[left_idx] < self.values[right_idx] {
                 greater_idx = right_idx;
             }
 
@@ -79,12 +189,36 @@ impl<T: Ord> HeapQ<T> {
         }
     }
 
+    // the minimum is always a leaf, and leaves fill the back half of the array
+    fn pop_min(&mut self) -> Option<T> {
+        let first_leaf = self.values.len() / 2;
+        let (offset, _) = self.values[first_leaf..]
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.cmp(b))?;
+        let index = first_leaf + offset;
+        let value = self.values.swap_remove(index);
+        // the value moved in came from the last leaf, so it can only need to move up
+        if index < self.values.len() {
+            self.siftup(index);
+        }
+        Some(value)
+    }
+
+    // the heap layout depends on push order, sorting gives a canonical view of the elements
+    fn sorted_refs(&self) -> Vec<&T> {
+        let mut refs: Vec<&T> = self.values.iter().collect();
+        refs.sort();
+        refs
+    }
+
     fn heapify(&mut self) {
-        let last_index = self.values.len() - 1;
-        if last_index <= 0 {
+        if self.values.len() <= 1 {
             return;
         }
 
+        let last_index = self.values.len() - 1;
+
         let last_parent_index = (last_index - 1) / 2;
         for i in (0..=last_parent_index).rev() {
             self.siftdown(i);
@@ -92,12 +226,164 @@ impl<T: Ord> HeapQ<T> {
     }
 }
 
+impl<T: Ord> Default for HeapQ<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> Extend<T> for HeapQ<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.extend_from(iter);
+    }
+}
+
+impl<T: Ord> FromIterator<T> for HeapQ<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+impl<T: Ord> PartialEq for HeapQ<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.sorted_refs() == other.sorted_refs()
+    }
+}
+
+impl<T: Ord> Eq for HeapQ<T> {}
+
+impl<T: Ord + Hash> Hash for HeapQ<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for value in self.sorted_refs() {
+            value.hash(state);
+        }
+    }
+}
+
+impl<T: Ord> IntoIterator for HeapQ<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { heap: self }
+    }
+}
+
+impl<T: Ord> Iterator for IntoIter<T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.len(), Some(self.heap.len()))
+    }
+}
+
+impl<T: Ord> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<T> {
+        self.heap.pop_min()
+    }
+}
+
+impl<T: Ord> ExactSizeIterator for IntoIter<T> {}
+
+impl<T: Ord> FusedIterator for IntoIter<T> {}
+
+impl<T: Ord> From<Vec<T>> for HeapQ<T> {
+    fn from(values: Vec<T>) -> Self {
+        let mut heap = HeapQ { values };
+        heap.heapify();
+        heap
+    }
+}
+
+// ascending order, matching BinaryHeap::into_sorted_vec
+impl<T: Ord> From<HeapQ<T>> for Vec<T> {
+    fn from(heap: HeapQ<T>) -> Self {
+        heap.into_ascending_vec()
+    }
+}
+
+impl<T: Ord> HeapQBuilder<T> {
+    // builds a MinHeapQ<T> instead, so values are pushed and popped as Reverse(value) and
+    // sifting needs no direction check
+    pub fn min(self) -> HeapQBuilder<Reverse<T>> {
+        HeapQBuilder {
+            capacity: self.capacity,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn build(self) -> HeapQ<T> {
+        HeapQ {
+            values: Vec::with_capacity(self.capacity.unwrap_or(0)),
+        }
+    }
+}
+
+// largest n values in descending order, keeping at most n + 1 values in memory
+pub fn nlargest<T: Ord>(n: usize, iter: impl IntoIterator<Item = T>) -> Vec<T> {
+    let mut heap = HeapQ::new();
+    for value in iter {
+        heap.push(Reverse(value));
+        if heap.len() > n {
+            let _ = heap.pop();
+        }
+    }
+    let mut result: Vec<T> =
+        std::iter::from_fn(|| heap.pop().map(|Reverse(value)| value)).collect();
+    result.reverse();
+    result
+}
+
+// smallest n values in ascending order, keeping at most n + 1 values in memory
+pub fn nsmallest<T: Ord>(n: usize, iter: impl IntoIterator<Item = T>) -> Vec<T> {
+    let mut heap = HeapQ::new();
+    for value in iter {
+        heap.push(value);
+        if heap.len() > n {
+            let _ = heap.pop();
+        }
+    }
+    let mut result: Vec<T> = std::iter::from_fn(|| heap.pop()).collect();
+    result.reverse();
+    result
+}
+
+// merges ascending runs into one ascending stream, holding one head per run;
+// equal values come out in run order since heads are tagged with their run's index
+pub fn kway_merge<T: Ord, I: Iterator<Item = T>>(mut runs: Vec<I>) -> impl Iterator<Item = T> {
+    let mut heads = HeapQ::builder().min().capacity(runs.len()).build();
+    for (run, iter) in runs.iter_mut().enumerate() {
+        if let Some(value) = iter.next() {
+            heads.push(Reverse((value, run)));
+        }
+    }
+    std::iter::from_fn(move || {
+        let Reverse((value, run)) = heads.pop()?;
+        if let Some(next) = runs[run].next() {
+            heads.push(Reverse((next, run)));
+        }
+        Some(value)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use rand::SeedableRng;
     use rand::seq::SliceRandom;
 
-    use super::HeapQ;
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    use super::{HeapQ, MinHeapQ, kway_merge, nlargest, nsmallest};
 
     #[test]
     fn empty_after_creation() {
@@ -169,4 +455,370 @@ mod tests {
         assert_eq!(*heap.top().unwrap(), 3);
         assert_eq!(heap.len(), 3);
     }
+
+    #[test]
+    fn nlargest_nsmallest_shuffled() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut numbers: Vec<i32> = (0..1000).collect();
+        numbers.shuffle(&mut rng);
+
+        assert_eq!(
+            nlargest(5, numbers.iter().copied()),
+            vec![999, 998, 997, 996, 995]
+        );
+        assert_eq!(nsmallest(5, numbers.iter().copied()), vec![0, 1, 2, 3, 4]);
+        assert_eq!(nlargest(0, numbers.iter().copied()), Vec::<i32>::new());
+        assert_eq!(nsmallest(10, [3, 1, 2]), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn vec_round_trip() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut numbers: Vec<i32> = (0..100).collect();
+        numbers.shuffle(&mut rng);
+
+        let mut heap = HeapQ::from(numbers.clone());
+        assert_eq!(heap.len(), 100);
+        assert_eq!(*heap.top().unwrap(), 99);
+
+        let sorted: Vec<i32> = heap.into();
+        numbers.sort();
+        assert_eq!(sorted, numbers);
+    }
+
+    #[test]
+    fn binary_heap_round_trip() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut numbers: Vec<i32> = (0..100).collect();
+        numbers.shuffle(&mut rng);
+
+        let std_heap = BinaryHeap::from(numbers.clone());
+        let heap = HeapQ::from_binary_heap(std_heap);
+        let std_heap = heap.into_binary_heap();
+        numbers.sort();
+        assert_eq!(std_heap.into_sorted_vec(), numbers);
+
+        let heap = HeapQ::from_binary_heap(BinaryHeap::from(numbers.clone()));
+        let sorted: Vec<i32> = heap.into();
+        assert_eq!(sorted, numbers);
+    }
+
+    #[test]
+    fn min_heap_alias() {
+        let mut heap: MinHeapQ<i32> = [3, 1, 2].into_iter().map(Reverse).collect();
+        assert_eq!(heap.pop(), Some(Reverse(1)));
+        assert_eq!(heap.pop(), Some(Reverse(2)));
+        assert_eq!(heap.pop(), Some(Reverse(3)));
+    }
+
+    #[test]
+    fn equal_heaps_hash_equally() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash_of(heap: &HeapQ<i32>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            heap.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let mut pushed = HeapQ::new();
+        for i in 0..20 {
+            pushed.push(i);
+        }
+        let heapified = HeapQ::from_iter((0..20).rev());
+
+        assert!(pushed == heapified);
+        assert_eq!(hash_of(&pushed), hash_of(&heapified));
+        assert_eq!(pushed.pop(), Some(19));
+        assert!(pushed != heapified);
+    }
+
+    #[test]
+    fn extend_from_small_and_large_batches() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut numbers: Vec<i32> = (0..100).collect();
+        numbers.shuffle(&mut rng);
+
+        // small batch relative to the heap sifts each value up
+        let mut sifted = HeapQ::from_iter(numbers[..90].iter().copied());
+        sifted.extend_from(numbers[90..].iter().copied());
+
+        // large batch relative to the heap re-heapifies
+        let mut rebuilt = HeapQ::from_iter(numbers[..10].iter().copied());
+        rebuilt.extend(numbers[10..].iter().copied());
+
+        for i in (0..100).rev() {
+            assert_eq!(sifted.pop(), Some(i));
+            assert_eq!(rebuilt.pop(), Some(i));
+        }
+        assert!(sifted.is_empty());
+        assert!(rebuilt.is_empty());
+    }
+
+    #[test]
+    fn iter_sorted_leaves_heap_intact() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut numbers: Vec<i32> = (0..50).collect();
+        numbers.shuffle(&mut rng);
+        let mut heap = HeapQ::from_iter(numbers);
+
+        let sorted: Vec<i32> = heap.iter_sorted().copied().collect();
+        assert_eq!(sorted, (0..50).rev().collect::<Vec<i32>>());
+
+        assert_eq!(heap.len(), 50);
+        assert_eq!(*heap.top().unwrap(), 49);
+        for i in (0..50).rev() {
+            assert_eq!(heap.pop(), Some(i));
+        }
+    }
+
+    #[test]
+    fn into_iter_from_both_ends() {
+        let mut values: Vec<i32> = (0..100).collect();
+        values.shuffle(&mut rand::rngs::StdRng::seed_from_u64(7));
+        let mut iter = HeapQ::from(values).into_iter();
+
+        let mut high = 99;
+        let mut low = 0;
+        for step in 0..50 {
+            if step % 3 == 0 {
+                assert_eq!(iter.next_back(), Some(low));
+                low += 1;
+            } else {
+                assert_eq!(iter.next(), Some(high));
+                high -= 1;
+            }
+        }
+        assert_eq!(iter.len(), 50);
+        let rest: Vec<i32> = iter.rev().collect();
+        assert_eq!(rest, (low..=high).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn is_valid_heap_detects_violation() {
+        let heap = HeapQ::from(vec![3, 9, 1, 4]);
+        assert!(heap.is_valid_heap());
+        assert!(HeapQ::<i32>::new().is_valid_heap());
+        let broken = HeapQ { values: vec![1, 2] };
+        assert!(!broken.is_valid_heap());
+    }
+
+    #[test]
+    fn all_equal_values() {
+        let mut heap = HeapQ::new();
+        for _ in 0..37 {
+            heap.push(5);
+            assert!(heap.is_valid_heap());
+        }
+        for remaining in (0..37).rev() {
+            assert_eq!(heap.pop(), Some(5));
+            assert_eq!(heap.len(), remaining);
+            assert!(heap.is_valid_heap());
+        }
+        assert_eq!(heap.pop(), None);
+    }
+
+    #[test]
+    fn many_duplicates_keep_counts() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(860);
+        let mut numbers: Vec<i32> = (0..300).map(|i| i % 4).collect();
+        numbers.shuffle(&mut rng);
+
+        // both build paths, sifting up one at a time and heapifying at once
+        let mut pushed = HeapQ::new();
+        for &number in &numbers {
+            pushed.push(number);
+        }
+        let heapified = HeapQ::from(numbers);
+        for mut heap in [pushed, heapified] {
+            assert!(heap.is_valid_heap());
+            let popped: Vec<i32> = std::iter::from_fn(|| heap.pop()).collect();
+            for value in 0..4 {
+                let start = (3 - value as usize) * 75;
+                assert_eq!(popped[start..start + 75], [value; 75]);
+            }
+        }
+    }
+
+    #[test]
+    fn pop_if_drains_above_threshold() {
+        let mut heap = HeapQ::from(vec![7, 2, 9, 4, 12, 1]);
+        let mut drained = Vec::new();
+        while let Some(value) = heap.pop_if(|top| *top > 4) {
+            drained.push(value);
+        }
+        assert_eq!(drained, vec![12, 9, 7]);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.top(), Some(&4));
+        assert!(heap.is_valid_heap());
+        assert_eq!(HeapQ::<i32>::new().pop_if(|_| true), None);
+    }
+
+    #[test]
+    fn builder_min_heap_with_capacity() {
+        let mut heap: MinHeapQ<i32> = HeapQ::builder().min().capacity(1000).build();
+        assert!(heap.capacity() >= 1000);
+
+        let mut values: Vec<i32> = (0..1000).collect();
+        values.shuffle(&mut rand::rngs::StdRng::seed_from_u64(7));
+        for value in values {
+            heap.push(Reverse(value));
+        }
+        assert!(heap.capacity() >= 1000);
+        assert!(heap.is_valid_heap());
+        assert_eq!(heap.top(), Some(&Reverse(0)));
+        assert!(heap.iter_sorted().take(3).map(|r| r.0).eq(0..3));
+        assert_eq!(heap.into_iter().next_back(), Some(Reverse(999)));
+
+        let mut default = HeapQ::builder().build();
+        default.extend([1, 3, 2]);
+        assert_eq!(default.pop(), Some(3));
+
+        // the direction is in the element type, so it survives the trip through BinaryHeap
+        let mut min_heap = HeapQ::builder().min().build();
+        min_heap.extend([3, 1, 2].map(Reverse));
+        let mut std_heap = min_heap.into_binary_heap();
+        assert_eq!(std_heap.pop(), Some(Reverse(1)));
+    }
+
+    #[test]
+    fn kway_merge_sorted_runs() {
+        let runs = vec![
+            vec![1, 4, 7, 10],
+            vec![],
+            vec![2, 2, 3, 11, 12],
+            vec![0],
+            vec![5, 6, 7, 8, 9],
+        ];
+        let mut expected: Vec<i32> = runs.concat();
+        expected.sort();
+        let merged: Vec<i32> = kway_merge(runs.into_iter().map(Vec::into_iter).collect()).collect();
+        assert_eq!(merged, expected);
+
+        let none: Vec<std::vec::IntoIter<i32>> = Vec::new();
+        assert_eq!(kway_merge(none).next(), None);
+    }
+
+    #[test]
+    fn pop_shrinking_releases_capacity() {
+        let mut heap = HeapQ::from((0..10_000).collect::<Vec<_>>());
+        let full = heap.capacity();
+        let mut expected = 10_000;
+        let mut shrunk_at = None;
+        while let Some(value) = heap.pop_shrinking() {
+            expected -= 1;
+            assert_eq!(value, expected);
+            if shrunk_at.is_none() && heap.capacity() < full {
+                shrunk_at = Some(heap.len());
+            }
+            assert!(heap.len() >= heap.capacity() / 4);
+        }
+        assert_eq!(shrunk_at, Some(2499));
+        assert!(heap.capacity() <= 1);
+    }
+
+    #[test]
+    fn drain_sorted_into_reuses_buffer() {
+        let mut out = vec![100, 200, 300];
+        let mut heap = HeapQ::from(vec![3, 1, 4, 1, 5, 9, 2, 6]);
+        heap.drain_sorted_into(&mut out);
+        assert_eq!(out, vec![9, 6, 5, 4, 3, 2, 1, 1]);
+        assert!(heap.is_empty());
+
+        let capacity = out.capacity();
+        heap.extend([7, 8]);
+        heap.drain_sorted_into(&mut out);
+        assert_eq!(out, vec![8, 7]);
+        assert_eq!(out.capacity(), capacity);
+
+        heap.drain_sorted_into(&mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn into_ascending_vec_sorts_in_place() {
+        let mut values: Vec<i32> = (0..1000).collect();
+        values.shuffle(&mut rand::rngs::StdRng::seed_from_u64(904));
+
+        let heap = HeapQ::from(values.clone());
+        let sorted = heap.into_ascending_vec();
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(sorted.len(), 1000);
+
+        let mut min_heap = HeapQ::builder().min().build();
+        min_heap.extend(values.into_iter().map(Reverse));
+        let sorted = min_heap.into_ascending_vec();
+        assert!(sorted.into_iter().map(|r| r.0).eq((0..1000).rev()));
+
+        assert!(HeapQ::<i32>::new().into_ascending_vec().is_empty());
+        assert_eq!(
+            HeapQ::from(vec![2, 2, 1]).into_ascending_vec(),
+            vec![1, 2, 2]
+        );
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::HeapQ;
+    use proptest::prelude::*;
+    use std::collections::BinaryHeap;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Push(i32),
+        Pop,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![(0..32).prop_map(Op::Push), Just(Op::Pop)]
+    }
+
+    proptest! {
+        #[test]
+        fn stays_valid_heap(ops in prop::collection::vec(op(), 0..300)) {
+            let mut heap = HeapQ::new();
+            for op in ops {
+                match op {
+                    Op::Push(value) => heap.push(value),
+                    Op::Pop => {
+                        let _ = heap.pop();
+                    }
+                }
+                prop_assert!(heap.is_valid_heap());
+            }
+        }
+
+        #[test]
+        fn matches_binary_heap(ops in prop::collection::vec(op(), 0..300)) {
+            let mut heap = HeapQ::new();
+            let mut reference = BinaryHeap::new();
+            for op in ops {
+                match op {
+                    Op::Push(value) => {
+                        heap.push(value);
+                        reference.push(value);
+                    }
+                    Op::Pop => prop_assert_eq!(heap.pop(), reference.pop()),
+                }
+                prop_assert_eq!(heap.top(), reference.peek());
+                prop_assert_eq!(heap.len(), reference.len());
+            }
+            while let Some(value) = reference.pop() {
+                prop_assert_eq!(heap.pop(), Some(value));
+            }
+            prop_assert!(heap.is_empty());
+        }
+
+        #[test]
+        fn all_equal_matches_binary_heap(value in 0..4i32, count in 0..100usize) {
+            let mut heap: HeapQ<i32> = std::iter::repeat_n(value, count).collect();
+            let mut reference: BinaryHeap<i32> = std::iter::repeat_n(value, count).collect();
+            while let Some(expected) = reference.pop() {
+                prop_assert_eq!(heap.pop(), Some(expected));
+            }
+            prop_assert_eq!(heap.pop(), None);
+        }
+    }
 }
@@ -1,5 +1,10 @@
+// the baseline tests spell out assert_eq!(x, true/false)
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
 pub mod avl_hashmap;
 pub mod avl_unsafe;
 pub mod avl_vec;
+pub mod handle_heap;
 pub mod heapq;
+pub mod priority_tree;
 pub mod tree;
@@ -0,0 +1,145 @@
+use crate::avl_vec::Tree;
+use crate::tree::TreeOps;
+
+// Double-ended priority queue on top of avl_vec: both ends are O(log n), unlike HeapQ which
+// only reaches its top cheaply. Each value is paired with a push counter so equal values can
+// all be stored: among ties pop_min takes the oldest and pop_max the newest.
+pub struct PriorityTree<T: Ord> {
+    tree: Tree<(T, u64)>,
+    pushed: u64,
+}
+
+impl<T: Ord> PriorityTree<T> {
+    pub fn new() -> Self {
+        PriorityTree {
+            tree: Tree::new(),
+            pushed: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        let _ = self.tree.insert((value, self.pushed));
+        self.pushed += 1;
+    }
+
+    pub fn pop_min(&mut self) -> Option<T> {
+        self.tree.take_min().map(|(value, _)| value)
+    }
+
+    pub fn pop_max(&mut self) -> Option<T> {
+        self.tree.take_max().map(|(value, _)| value)
+    }
+
+    pub fn peek_min(&self) -> Option<&T> {
+        self.tree.first_value().map(|(value, _)| value)
+    }
+
+    pub fn peek_max(&self) -> Option<&T> {
+        self.tree.last_value().map(|(value, _)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl<T: Ord> Default for PriorityTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::PriorityTree;
+
+    #[test]
+    fn empty_queue() {
+        let mut queue = PriorityTree::<i32>::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.peek_min(), None);
+        assert_eq!(queue.peek_max(), None);
+        assert_eq!(queue.pop_min(), None);
+        assert_eq!(queue.pop_max(), None);
+    }
+
+    #[test]
+    fn pops_from_both_ends() {
+        let mut queue = PriorityTree::new();
+        for value in [5, 1, 4, 1, 5, 9, 2, 6] {
+            queue.push(value);
+        }
+        assert_eq!(queue.len(), 8);
+        assert_eq!(queue.peek_min(), Some(&1));
+        assert_eq!(queue.peek_max(), Some(&9));
+        assert_eq!(queue.pop_max(), Some(9));
+        assert_eq!(queue.pop_min(), Some(1));
+        assert_eq!(queue.pop_min(), Some(1));
+        assert_eq!(queue.pop_max(), Some(6));
+        assert_eq!(queue.pop_max(), Some(5));
+        assert_eq!(queue.pop_max(), Some(5));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn ties_by_push_order() {
+        #[derive(Debug)]
+        struct Job(u8, &'static str);
+        impl PartialEq for Job {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+        impl Eq for Job {}
+        impl PartialOrd for Job {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Job {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let mut queue = PriorityTree::new();
+        for (priority, name) in [(1, "a"), (1, "b"), (1, "c"), (1, "d")] {
+            queue.push(Job(priority, name));
+        }
+        assert_eq!(queue.pop_min().map(|job| job.1), Some("a"));
+        assert_eq!(queue.pop_max().map(|job| job.1), Some("d"));
+        assert_eq!(queue.pop_min().map(|job| job.1), Some("b"));
+        assert_eq!(queue.pop_max().map(|job| job.1), Some("c"));
+    }
+
+    #[test]
+    fn random_ops_match_sorted_vec() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(876);
+        let mut queue = PriorityTree::new();
+        let mut reference: Vec<i32> = Vec::new();
+        for _ in 0..5000 {
+            match rng.random_range(0..4) {
+                0 | 1 => {
+                    let value = rng.random_range(0..50);
+                    queue.push(value);
+                    let at = reference.partition_point(|&x| x <= value);
+                    reference.insert(at, value);
+                }
+                2 => {
+                    let expected = (!reference.is_empty()).then(|| reference.remove(0));
+                    assert_eq!(queue.pop_min(), expected);
+                }
+                _ => assert_eq!(queue.pop_max(), reference.pop()),
+            }
+            assert_eq!(queue.len(), reference.len());
+            assert_eq!(queue.peek_min(), reference.first());
+            assert_eq!(queue.peek_max(), reference.last());
+        }
+    }
+}
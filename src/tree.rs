@@ -1,6 +1,596 @@
+use crate::{avl_hashmap, avl_unsafe, avl_vec};
+#[cfg(feature = "stats")]
+use std::cell::Cell;
+#[cfg(feature = "stats")]
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertResult {
+    Inserted,
+    AlreadyPresent,
+}
+
+// the first value that compared equal to an earlier one, when duplicates are an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateError<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Display for DuplicateError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "duplicate value {:?}", self.0)
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for DuplicateError<T> {}
+
+// shape summary for comparing backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeStats {
+    pub len: usize,
+    pub height: i32,
+    pub is_balanced: bool,
+}
+
+// what insert does with a value that compares equal to one already stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    // keep the stored value and drop the new one
+    #[default]
+    Reject,
+    // swap the stored value for the new one, useful when Ord ignores part of the value
+    Replace,
+}
+
 pub trait TreeOps<T> {
+    // false means the value was already present (insert) or missing (remove)
+    #[must_use]
     fn insert(&mut self, value: T) -> bool;
+    #[must_use]
     fn contains(&self, value: &T) -> bool;
+    #[must_use]
     fn remove(&mut self, value: &T) -> bool;
     fn len(&self) -> usize;
+    // edges on the longest root to leaf path, -1 when empty
+    fn height(&self) -> i32;
+    // O(n): every node's subtrees differ in height by at most one, with heights measured
+    // from the structure rather than read from the nodes
+    fn is_balanced(&self) -> bool;
+    // the values in order, boxed so the trait stays object safe
+    fn boxed_iter(&self) -> Box<dyn Iterator<Item = &T> + '_>;
+
+    // in-order walk that stops as soon as f returns false
+    fn visit_while(&self, f: &mut dyn FnMut(&T) -> bool) {
+        for value in self.boxed_iter() {
+            if !f(value) {
+                break;
+            }
+        }
+    }
+
+    // whether any stored value lies in lo..=hi; the default walks values up to hi
+    fn any_in_bounds(&self, lo: &T, hi: &T) -> bool
+    where
+        T: Ord,
+    {
+        let mut found = false;
+        self.visit_while(&mut |value| {
+            found = value >= lo && value <= hi;
+            !found && value <= hi
+        });
+        found
+    }
+
+    fn stats(&self) -> TreeStats {
+        TreeStats {
+            len: self.len(),
+            height: self.height(),
+            is_balanced: self.is_balanced(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // returns how many values were new; takes a dyn iterator so the trait stays object safe
+    fn insert_all(&mut self, values: &mut dyn Iterator<Item = T>) -> usize {
+        values.fold(0, |count, value| count + usize::from(self.insert(value)))
+    }
+
+    fn insert_checked(&mut self, value: T) -> InsertResult {
+        if self.insert(value) {
+            InsertResult::Inserted
+        } else {
+            InsertResult::AlreadyPresent
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+thread_local! {
+    static COMPARISONS: Cell<u64> = const { Cell::new(0) };
+}
+
+// Counts every comparison between two wrapped keys, so any backend can be measured on the
+// same workload by storing CountingKey<T> instead of T. The count is per thread.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone)]
+pub struct CountingKey<T>(pub T);
+
+#[cfg(feature = "stats")]
+impl<T: Ord> PartialEq for CountingKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+#[cfg(feature = "stats")]
+impl<T: Ord> Eq for CountingKey<T> {}
+
+#[cfg(feature = "stats")]
+impl<T: Ord> PartialOrd for CountingKey<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "stats")]
+impl<T: Ord> Ord for CountingKey<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        COMPARISONS.with(|count| count.set(count.get() + 1));
+        self.0.cmp(&other.0)
+    }
+}
+
+// CountingKey comparisons on this thread since the last take_comparison_count
+#[cfg(feature = "stats")]
+pub fn comparison_count() -> u64 {
+    COMPARISONS.with(Cell::get)
+}
+
+// like comparison_count, and starts the count again from zero
+#[cfg(feature = "stats")]
+pub fn take_comparison_count() -> u64 {
+    COMPARISONS.with(|count| count.replace(0))
+}
+
+// folds a tree bottom up, combining each node with its children's results; the walk keeps
+// its own stack so a degenerate tree (see avl_vec's set_rebalance) can't overflow the call stack
+pub(crate) fn fold_subtrees<N: Copy, A: Clone, E>(
+    root: Option<N>,
+    empty: A,
+    children: impl Fn(N) -> (Option<N>, Option<N>),
+    mut combine: impl FnMut(N, A, A) -> Result<A, E>,
+) -> Result<A, E> {
+    // a node is pushed once to queue its children and again to combine their results
+    let mut stack = vec![(root, false)];
+    let mut results = Vec::new();
+    while let Some((link, children_done)) = stack.pop() {
+        let Some(node) = link else {
+            results.push(empty.clone());
+            continue;
+        };
+        if children_done {
+            let right = results.pop().expect("Right subtree was folded");
+            let left = results.pop().expect("Left subtree was folded");
+            results.push(combine(node, left, right)?);
+        } else {
+            let (left, right) = children(node);
+            stack.extend([(Some(node), true), (right, false), (left, false)]);
+        }
+    }
+    Ok(results.pop().expect("Root was folded"))
+}
+
+// whether every node's subtrees differ in measured height by at most one
+pub(crate) fn is_balanced_by<N: Copy>(
+    root: Option<N>,
+    children: impl Fn(N) -> (Option<N>, Option<N>),
+) -> bool {
+    fold_subtrees(root, -1, children, |_, left: i32, right: i32| {
+        if (left - right).abs() <= 1 {
+            Ok(1 + left.max(right))
+        } else {
+            Err(())
+        }
+    })
+    .is_ok()
+}
+
+// builds a backend by its benchmark name: "hashmap", "unsafe" or "vec"
+pub fn make_tree<T: Ord + 'static>(kind: &str) -> Option<Box<dyn TreeOps<T>>> {
+    match kind {
+        "hashmap" => Some(Box::new(avl_hashmap::Tree::new())),
+        "unsafe" => Some(Box::new(avl_unsafe::Tree::new())),
+        "vec" => Some(Box::new(avl_vec::Tree::new())),
+        _ => None,
+    }
+}
+
+// moves values into a fresh backend of any type, e.g. rebuild_as::<avl_hashmap::Tree<_>, _>(tree)
+pub fn rebuild_as<Dst: TreeOps<T> + Default, T: Ord>(values: impl IntoIterator<Item = T>) -> Dst {
+    let mut tree = Dst::default();
+    for value in values {
+        let _ = tree.insert(value);
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    // orders by key only, so a duplicate can carry a different payload
+    #[derive(Debug)]
+    struct Keyed(i32, &'static str);
+
+    impl PartialEq for Keyed {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Keyed {}
+
+    impl PartialOrd for Keyed {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Keyed {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    fn fill(tree: &mut dyn TreeOps<Keyed>) {
+        assert!(tree.insert(Keyed(1, "a")));
+        assert!(tree.insert(Keyed(2, "b")));
+        assert!(!tree.insert(Keyed(1, "c")));
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn insert_checked_both_variants() {
+        let trees: Vec<Box<dyn TreeOps<i32>>> = vec![
+            Box::new(avl_hashmap::Tree::new()),
+            Box::new(avl_unsafe::Tree::new()),
+            Box::new(avl_vec::Tree::new()),
+        ];
+        for mut tree in trees {
+            assert_eq!(tree.insert_checked(1), InsertResult::Inserted);
+            assert_eq!(tree.insert_checked(2), InsertResult::Inserted);
+            assert_eq!(tree.insert_checked(1), InsertResult::AlreadyPresent);
+            assert_eq!(tree.len(), 2);
+        }
+    }
+
+    #[test]
+    fn duplicate_policy_reject_keeps_first() {
+        let mut tree = avl_vec::Tree::new();
+        fill(&mut tree);
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["a", "b"]);
+
+        let mut tree = avl_unsafe::Tree::new();
+        fill(&mut tree);
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["a", "b"]);
+
+        let mut tree = avl_hashmap::Tree::new();
+        fill(&mut tree);
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn duplicate_policy_replace_keeps_last() {
+        let mut tree = avl_vec::Tree::new();
+        tree.set_duplicate_policy(DuplicatePolicy::Replace);
+        fill(&mut tree);
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["c", "b"]);
+        let hint = tree.insert_ref(Keyed(1, "c"));
+        let (node, new) = tree.insert_after_hint(Keyed(2, "d"), hint);
+        assert!(!new);
+        assert_eq!(tree.value_at(node).map(|k| k.1), Some("d"));
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["c", "d"]);
+
+        let mut tree = avl_unsafe::Tree::new();
+        tree.set_duplicate_policy(DuplicatePolicy::Replace);
+        fill(&mut tree);
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["c", "b"]);
+
+        let mut tree = avl_hashmap::Tree::new();
+        tree.set_duplicate_policy(DuplicatePolicy::Replace);
+        fill(&mut tree);
+        let payloads: Vec<_> = tree.iter().map(|k| k.1).collect();
+        assert_eq!(payloads, vec!["c", "b"]);
+    }
+
+    #[test]
+    fn make_tree_by_name() {
+        for kind in ["hashmap", "unsafe", "vec"] {
+            let mut tree = make_tree::<i32>(kind).expect("Known tree kind");
+            assert!(tree.is_empty());
+            assert!(tree.insert(3));
+            assert!(tree.contains(&3));
+            assert!(tree.remove(&3));
+            assert!(tree.is_empty());
+        }
+        assert!(make_tree::<i32>("btree").is_none());
+    }
+
+    #[test]
+    fn insert_all_counts_new_values() {
+        for kind in ["hashmap", "unsafe", "vec"] {
+            let mut tree = make_tree::<i32>(kind).expect("Known tree kind");
+            assert_eq!(tree.insert_all(&mut [3, 1, 3, 2, 1].into_iter()), 3);
+            assert_eq!(tree.insert_all(&mut (2..6)), 2);
+            assert_eq!(tree.len(), 5);
+        }
+    }
+
+    #[test]
+    fn height_after_sequential_inserts() {
+        for kind in ["hashmap", "unsafe", "vec"] {
+            let mut tree = make_tree::<i32>(kind).expect("Known tree kind");
+            assert_eq!(tree.height(), -1);
+            for i in 0..100 {
+                assert!(tree.insert(i));
+            }
+            // an unbalanced BST would reach 99 here, AVL keeps sorted input at the minimum
+            assert_eq!(tree.height(), 6);
+        }
+    }
+
+    #[test]
+    fn rebuild_as_other_backends() {
+        let mut source = avl_vec::Tree::new();
+        source.extend([5, 3, 9, 1, 7]);
+
+        let hashmap: avl_hashmap::Tree<i32> = rebuild_as(source.iter().copied());
+        let pointers: avl_unsafe::Tree<i32> = rebuild_as(hashmap.iter().copied());
+        assert!(hashmap.iter().eq(source.iter()));
+        assert!(pointers.iter().eq(source.iter()));
+
+        let back: avl_vec::Tree<i32> = rebuild_as(pointers);
+        assert_eq!(back, source);
+    }
+
+    // no `use` of TreeOps here, the concrete types must work without it
+    mod without_trait {
+        use crate::{avl_hashmap, avl_unsafe, avl_vec};
+
+        #[test]
+        fn inherent_len_and_is_empty() {
+            let vec_tree = avl_vec::Tree::<i32>::new();
+            let unsafe_tree = avl_unsafe::Tree::<i32>::new();
+            let hashmap_tree = avl_hashmap::Tree::<i32>::new();
+            let multi_tree = avl_vec::MultiTree::<i32>::new();
+            assert_eq!(vec_tree.len(), 0);
+            assert_eq!(unsafe_tree.len(), 0);
+            assert_eq!(hashmap_tree.len(), 0);
+            assert_eq!(multi_tree.len(), 0);
+            assert!(vec_tree.is_empty());
+            assert!(unsafe_tree.is_empty());
+            assert!(hashmap_tree.is_empty());
+            assert!(multi_tree.is_empty());
+        }
+    }
+
+    #[test]
+    fn stats_for_sorted_input() {
+        for kind in ["hashmap", "unsafe", "vec"] {
+            let mut tree = make_tree::<i32>(kind).unwrap();
+            assert_eq!(
+                tree.stats(),
+                TreeStats {
+                    len: 0,
+                    height: -1,
+                    is_balanced: true
+                }
+            );
+            assert_eq!(tree.insert_all(&mut (0..1023)), 1023);
+            assert_eq!(
+                tree.stats(),
+                TreeStats {
+                    len: 1023,
+                    height: 9,
+                    is_balanced: true
+                },
+                "{kind}"
+            );
+        }
+        let mut multi = avl_vec::MultiTree::new();
+        assert_eq!(multi.insert_all(&mut [1, 1, 2].into_iter()), 3);
+        assert_eq!(
+            multi.stats(),
+            TreeStats {
+                len: 3,
+                height: 1,
+                is_balanced: true
+            }
+        );
+    }
+
+    #[test]
+    fn any_in_bounds_default_matches_override() {
+        let values: Vec<i32> = (0..50).map(|i| i * 4 + 1).collect();
+        let mut trees: Vec<Box<dyn TreeOps<i32>>> = ["hashmap", "unsafe", "vec"]
+            .into_iter()
+            .map(|kind| make_tree::<i32>(kind).unwrap())
+            .collect();
+        trees.push(Box::new(avl_vec::MultiTree::new()));
+        for tree in trees.iter_mut() {
+            assert_eq!(tree.insert_all(&mut values.iter().copied()), 50);
+        }
+
+        for lo in -3..205 {
+            for hi in [lo - 1, lo, lo + 1, lo + 2, lo + 3, lo + 10] {
+                let expected = values.iter().any(|v| (lo..=hi).contains(v));
+                for tree in trees.iter() {
+                    assert_eq!(tree.any_in_bounds(&lo, &hi), expected, "{lo}..={hi}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn visit_while_stops_early() {
+        let mut trees: Vec<Box<dyn TreeOps<i32>>> = ["hashmap", "unsafe", "vec"]
+            .into_iter()
+            .map(|kind| make_tree::<i32>(kind).unwrap())
+            .collect();
+        trees.push(Box::new(avl_vec::MultiTree::new()));
+        for tree in trees.iter_mut() {
+            tree.insert_all(&mut [5, 1, 4, 1, 3, 2].into_iter());
+            let mut seen = Vec::new();
+            tree.visit_while(&mut |&value| {
+                seen.push(value);
+                value < 3
+            });
+            assert_eq!(seen, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn drop_frees_all_nodes() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct Counted(i32, Rc<Cell<usize>>);
+        impl PartialEq for Counted {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+        impl Eq for Counted {}
+        impl PartialOrd for Counted {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Counted {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.1.set(self.1.get() + 1);
+            }
+        }
+
+        for kind in ["hashmap", "unsafe", "vec"] {
+            let dropped = Rc::new(Cell::new(0));
+            let mut tree = make_tree::<Counted>(kind).unwrap();
+            for i in 0..1000 {
+                assert!(tree.insert(Counted(i, dropped.clone())));
+            }
+            for i in (0..1000).step_by(3) {
+                assert!(tree.remove(&Counted(i, dropped.clone())));
+            }
+            dropped.set(0);
+            drop(tree);
+            assert_eq!(dropped.get(), 1000 - 334, "{kind}");
+        }
+    }
+
+    // the concrete iterators, which TreeOps doesn't expose
+    fn size_hint_tracks_remaining_in<Tr>()
+    where
+        Tr: TreeOps<i32> + Default + IntoIterator<Item = i32>,
+        for<'a> &'a Tr: IntoIterator<Item = &'a i32>,
+    {
+        let mut tree = Tr::default();
+        assert_eq!(tree.insert_all(&mut (0..10)), 10);
+
+        let mut iter = (&tree).into_iter();
+        assert_eq!(iter.size_hint(), (10, Some(10)));
+        iter.next();
+        iter.next();
+        assert_eq!(iter.size_hint(), (8, Some(8)));
+        drop(iter);
+
+        let mut into_iter = tree.into_iter();
+        assert_eq!(into_iter.size_hint(), (10, Some(10)));
+        into_iter.next();
+        assert_eq!(into_iter.size_hint(), (9, Some(9)));
+    }
+
+    #[test]
+    fn size_hint_tracks_remaining() {
+        size_hint_tracks_remaining_in::<avl_hashmap::Tree<i32>>();
+        size_hint_tracks_remaining_in::<avl_unsafe::Tree<i32>>();
+        size_hint_tracks_remaining_in::<avl_vec::Tree<i32>>();
+    }
+
+    fn debug_lists_values_in_order_in<Tr: TreeOps<i32> + Default + fmt::Debug>() {
+        let mut tree = Tr::default();
+        assert_eq!(tree.insert_all(&mut [3, 1, 2].into_iter()), 3);
+        assert_eq!(format!("{:?}", tree), "{1, 2, 3}");
+        assert_eq!(format!("{:?}", Tr::default()), "{}");
+    }
+
+    #[test]
+    fn debug_lists_values_in_order() {
+        debug_lists_values_in_order_in::<avl_hashmap::Tree<i32>>();
+        debug_lists_values_in_order_in::<avl_unsafe::Tree<i32>>();
+        debug_lists_values_in_order_in::<avl_vec::Tree<i32>>();
+    }
+
+    fn iter_by_reference_in<Tr>()
+    where
+        Tr: TreeOps<i32> + Default,
+        for<'a> &'a Tr: IntoIterator<Item = &'a i32>,
+    {
+        let mut tree = Tr::default();
+        assert_eq!(tree.insert_all(&mut (0..10).rev()), 10);
+
+        let mut expected = 0;
+        for &value in &tree {
+            assert_eq!(value, expected);
+            expected += 1;
+        }
+        assert_eq!(expected, 10);
+    }
+
+    #[test]
+    fn iter_by_reference() {
+        iter_by_reference_in::<avl_hashmap::Tree<i32>>();
+        iter_by_reference_in::<avl_unsafe::Tree<i32>>();
+        iter_by_reference_in::<avl_vec::Tree<i32>>();
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn counting_key_counts_in_every_backend() {
+        // comparisons for a hit and a miss on a single-element tree: avl_vec's search returns
+        // the ordering it found, the other two compare the closest node once more
+        for (kind, hit, miss) in [("hashmap", 2, 2), ("unsafe", 2, 2), ("vec", 1, 1)] {
+            let mut tree = make_tree::<CountingKey<i32>>(kind).unwrap();
+            assert!(tree.insert(CountingKey(5)));
+            take_comparison_count();
+            assert!(tree.contains(&CountingKey(5)));
+            assert_eq!(take_comparison_count(), hit, "{kind} hit");
+            assert!(!tree.contains(&CountingKey(7)));
+            assert_eq!(take_comparison_count(), miss, "{kind} miss");
+
+            for i in 0..1000 {
+                let _ = tree.insert(CountingKey(i));
+            }
+            take_comparison_count();
+            for i in 0..1000 {
+                assert!(tree.contains(&CountingKey(i)));
+            }
+            // a balanced tree of 1000 needs about log2(1000) comparisons per lookup
+            let per_lookup = take_comparison_count() as f64 / 1000.0;
+            assert!((5.0..25.0).contains(&per_lookup), "{kind}: {per_lookup}");
+        }
+    }
 }